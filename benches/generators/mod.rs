@@ -0,0 +1,106 @@
+//! Generates the subscriptions and publish topics of the benchmarks. The generators are seeded, so
+//! every run measures the same trees.
+
+use mqtt_topic_tree::ClientId;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// The subscriptions of a workload as `(filter, client)`, and topics that are published to it
+pub struct Workload {
+    pub subscriptions: Vec<(String, ClientId)>,
+    pub topics: Vec<String>,
+}
+
+const DEVICE_TYPES: [&str; 4] = ["sensor", "thermostat", "meter", "camera"];
+const METRICS: [&str; 6] = ["temperature", "humidity", "power", "status", "battery", "rssi"];
+
+/// Devices spread over sites, buildings and floors that publish metrics as
+/// `site/building/floor/type/device/metric`. Every device listens for its own commands, every
+/// site has a dashboard, and a few services consume metrics across all sites with wildcards.
+pub fn iot(devices: usize, seed: u64) -> Workload {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut subscriptions = Vec::new();
+    let mut topics = Vec::new();
+    for device in 0..devices {
+        let site = rng.random_range(0..10);
+        let building = rng.random_range(0..5);
+        let floor = rng.random_range(0..10);
+        let device_type = DEVICE_TYPES[rng.random_range(0..DEVICE_TYPES.len())];
+        let prefix = format!("site{site}/building{building}/floor{floor}/{device_type}/dev{device}");
+        subscriptions.push((format!("{prefix}/cmd/#"), device as ClientId));
+        for metric in METRICS.iter().take(rng.random_range(1..=METRICS.len())) {
+            topics.push(format!("{prefix}/{metric}"));
+        }
+    }
+    let client_id = devices as ClientId;
+    for site in 0..10 {
+        subscriptions.push((format!("site{site}/#"), client_id + site));
+    }
+    let services = [
+        "+/+/+/sensor/+/temperature",
+        "+/+/+/+/+/battery",
+        "+/+/+/meter/#",
+        "$share/ingest/+/+/+/+/+/+",
+        "$SYS/#",
+    ];
+    for (i, filter) in services.iter().enumerate() {
+        subscriptions.push((filter.to_string(), client_id + 10 + i as ClientId));
+    }
+    Workload { subscriptions, topics }
+}
+
+/// Users that are members of a few rooms each. Users subscribe to their inbox and the messages
+/// of their rooms, moderators follow every room, and presence is published per user.
+pub fn chat(users: usize, rooms: usize, seed: u64) -> Workload {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut subscriptions = Vec::new();
+    let mut topics = Vec::new();
+    for user in 0..users {
+        let client_id = user as ClientId;
+        subscriptions.push((format!("chat/users/user{user}/inbox"), client_id));
+        for _ in 0..rng.random_range(1..=5) {
+            let room = rng.random_range(0..rooms);
+            subscriptions.push((format!("chat/rooms/room{room}/messages"), client_id));
+            topics.push(format!("chat/rooms/room{room}/presence/user{user}"));
+        }
+        topics.push(format!("chat/users/user{user}/inbox"));
+    }
+    for room in 0..rooms {
+        topics.push(format!("chat/rooms/room{room}/messages"));
+    }
+    for moderator in 0..10 {
+        let client_id = (users + moderator) as ClientId;
+        subscriptions.push(("chat/rooms/+/messages".to_owned(), client_id));
+        subscriptions.push(("chat/rooms/+/presence/#".to_owned(), client_id));
+    }
+    Workload { subscriptions, topics }
+}
+
+/// Filters of `depth` levels, where every level is one of `width` names or, with the given chance,
+/// a `+`. A fraction of the filters end in a `#` instead of their last level.
+pub fn wildcards(filters: usize, depth: usize, width: usize, wildcard_chance: f64, seed: u64) -> Workload {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut subscriptions = Vec::new();
+    for client_id in 0..filters {
+        let mut levels: Vec<String> = (0..depth)
+            .map(|_| match rng.random_bool(wildcard_chance) {
+                true => "+".to_owned(),
+                false => format!("l{}", rng.random_range(0..width)),
+            })
+            .collect();
+        if rng.random_bool(wildcard_chance) {
+            let keep = rng.random_range(0..depth);
+            levels.truncate(keep);
+            levels.push("#".to_owned());
+        }
+        subscriptions.push((levels.join("/"), client_id as ClientId));
+    }
+    let topics = (0..1000)
+        .map(|_| {
+            let levels: Vec<String> =
+                (0..depth).map(|_| format!("l{}", rng.random_range(0..width))).collect();
+            levels.join("/")
+        })
+        .collect();
+    Workload { subscriptions, topics }
+}
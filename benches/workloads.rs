@@ -0,0 +1,351 @@
+//! Criterion benchmarks of the topic trees under realistic workloads.
+//!
+//! Run them with `cargo bench --bench workloads`, a single group can be selected with a filter,
+//! e.g. `cargo bench --bench workloads -- contention`. The features that change the lookups can
+//! be compared by running them again with e.g. `--features fast-hash`, `--features intern` or
+//! `--features rcu`.
+
+mod generators;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use generators::Workload;
+use mqtt_topic_tree::{
+    ClientId, MqttTopicTree, QoS, ShardedMqttTopicTree, TopicFilter, TopicName, TopicTree,
+};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+fn filter(filter: &str) -> TopicFilter {
+    TopicFilter::try_from(filter).unwrap()
+}
+
+fn build_tree(workload: &Workload) -> TopicTree {
+    let mut tree = TopicTree::default();
+    for (topic_filter, client_id) in workload.subscriptions.iter() {
+        tree.add_subscription(filter(topic_filter), *client_id, QoS::Level1).unwrap();
+    }
+    tree
+}
+
+fn topics(workload: &Workload) -> Vec<TopicName> {
+    workload
+        .topics
+        .iter()
+        .map(|x| TopicName::try_from(x.as_str()).unwrap())
+        .collect()
+}
+
+/// Looks up the topics of the workload in turn, in a TopicTree, a MqttTopicTree and a
+/// ShardedMqttTopicTree
+fn bench_lookups(c: &mut Criterion, group_name: &str, workloads: Vec<(String, Workload)>) {
+    let mut group = c.benchmark_group(group_name);
+    group.throughput(Throughput::Elements(1));
+    for (name, workload) in workloads {
+        let tree = build_tree(&workload);
+        let topics = topics(&workload);
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("TopicTree", &name), |b| {
+            b.iter(|| {
+                i = (i + 1) % topics.len();
+                tree.get_subscriptions(&topics[i])
+            })
+        });
+        let mqtt_tree = MqttTopicTree::from_topic_tree(tree);
+        group.bench_function(BenchmarkId::new("MqttTopicTree", &name), |b| {
+            b.iter(|| {
+                i = (i + 1) % topics.len();
+                mqtt_tree.get_subscriptions(&topics[i])
+            })
+        });
+        let sharded = ShardedMqttTopicTree::default();
+        for (topic_filter, client_id) in workload.subscriptions.iter() {
+            sharded.add_subscription(filter(topic_filter), *client_id, QoS::Level1).unwrap();
+        }
+        group.bench_function(BenchmarkId::new("ShardedMqttTopicTree", &name), |b| {
+            b.iter(|| {
+                i = (i + 1) % topics.len();
+                sharded.get_subscriptions(&topics[i])
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A few common topic shapes
+fn topic_shapes(c: &mut Criterion) {
+    let shape = |filters: Vec<String>, topics: Vec<String>| {
+        let subscriptions = filters.into_iter().zip(0..).collect();
+        Workload { subscriptions, topics }
+    };
+    let mut workloads = Vec::new();
+    // The three overlapping wildcards the crate was first measured with
+    let filters = ["home/+/+", "home/#", "home/+/#"].map(String::from).to_vec();
+    workloads.push(("wildcards".to_owned(), shape(filters, vec!["home/bedroom/light".to_owned()])));
+    // A home automation setup, every device listens for its commands and every room has a
+    // dashboard
+    let mut filters = Vec::new();
+    let mut topics = Vec::new();
+    for room in 0..20 {
+        filters.push(format!("home/room{room}/#"));
+        for device in 0..10 {
+            filters.push(format!("home/room{room}/device{device}/set"));
+            topics.push(format!("home/room{room}/device{device}/state"));
+            topics.push(format!("home/room{room}/device{device}/set"));
+        }
+    }
+    workloads.push(("home".to_owned(), shape(filters, topics)));
+    // A fleet of devices that publish telemetry, which a few services consume with wildcards
+    let mut filters: Vec<String> = (0..10_000).map(|x| format!("fleet/dev{x}/cmd")).collect();
+    filters.extend(
+        [
+            "fleet/+/telemetry/#",
+            "fleet/+/telemetry/temperature",
+            "$share/ingest/fleet/+/telemetry/#",
+            "$SYS/#",
+        ]
+        .map(String::from),
+    );
+    let topics = (0..10_000)
+        .step_by(97)
+        .flat_map(|x| {
+            [
+                format!("fleet/dev{x}/telemetry/temperature"),
+                format!("fleet/dev{x}/telemetry/battery"),
+                format!("fleet/dev{x}/cmd"),
+            ]
+        })
+        .collect();
+    workloads.push(("fleet".to_owned(), shape(filters, topics)));
+    // Sparkplug B, host applications subscribe per group and edge node
+    let mut filters = vec!["spBv1.0/+/NDEATH/#".to_owned(), "spBv1.0/#".to_owned()];
+    let mut topics = Vec::new();
+    for group in 0..10 {
+        for edge in 0..50 {
+            filters.push(format!("spBv1.0/group{group}/+/edge{edge}/#"));
+            topics.push(format!("spBv1.0/group{group}/DDATA/edge{edge}/device{}", edge % 7));
+        }
+    }
+    workloads.push(("sparkplug".to_owned(), shape(filters, topics)));
+    bench_lookups(c, "topic_shapes", workloads);
+}
+
+fn topic_sets(c: &mut Criterion) {
+    bench_lookups(
+        c,
+        "topic_sets",
+        vec![
+            ("iot_1k".to_owned(), generators::iot(1_000, 1)),
+            ("iot_100k".to_owned(), generators::iot(100_000, 1)),
+            ("chat_10k".to_owned(), generators::chat(10_000, 500, 2)),
+        ],
+    );
+}
+
+/// Trees of up to 32 levels, with a few wildcards on the way down
+fn deep_trees(c: &mut Criterion) {
+    let workloads = [4, 12, 32]
+        .into_iter()
+        .map(|depth| (format!("depth_{depth}"), generators::wildcards(5_000, depth, 3, 0.05, 3)))
+        .collect();
+    bench_lookups(c, "deep_trees", workloads);
+}
+
+/// Filters where a large part of the levels are `+` or end in `#`, which makes the lookups
+/// follow many branches at once
+fn wildcard_heavy(c: &mut Criterion) {
+    let workloads = [0.1, 0.3, 0.5]
+        .into_iter()
+        .map(|chance| {
+            let name = format!("wildcards_{}pct", (chance * 100.0) as u32);
+            (name, generators::wildcards(10_000, 6, 8, chance, 4))
+        })
+        .collect();
+    bench_lookups(c, "wildcard_heavy", workloads);
+}
+
+/// Many clients subscribed to the same filters, so a single lookup returns a lot of subscribers
+fn fan_out(c: &mut Criterion) {
+    let workloads = [10, 1_000, 10_000]
+        .into_iter()
+        .map(|clients| {
+            let subscriptions = (0..clients)
+                .flat_map(|x| {
+                    [("news/#".to_owned(), x), (format!("news/+/item{}", x % 10), x)]
+                })
+                .collect();
+            let topics = (0..10).map(|x| format!("news/sports/item{x}")).collect();
+            (format!("clients_{clients}"), Workload { subscriptions, topics })
+        })
+        .collect();
+    bench_lookups(c, "fan_out", workloads);
+}
+
+/// Shared groups with many members, and many groups on the same filter
+fn shared_groups(c: &mut Criterion) {
+    let workloads = [(1, 1_000), (100, 10), (1_000, 2)]
+        .into_iter()
+        .map(|(groups, members)| {
+            let subscriptions = (0..groups * members)
+                .map(|x| (format!("$share/group{}/jobs/+/run", x % groups), x as ClientId))
+                .collect();
+            let topics = (0..10).map(|x| format!("jobs/queue{x}/run")).collect();
+            (format!("{groups}x{members}"), Workload { subscriptions, topics })
+        })
+        .collect();
+    bench_lookups(c, "shared_groups", workloads);
+}
+
+/// Lookups while other threads write to the tree, and writes while other threads read from it
+fn contention(c: &mut Criterion) {
+    let workload = generators::iot(10_000, 5);
+    let topics = topics(&workload);
+    let tree = MqttTopicTree::from_topic_tree(build_tree(&workload));
+    let mut group = c.benchmark_group("contention");
+    for writers in [0, 1, 2] {
+        group.bench_function(BenchmarkId::new("lookup_while_writing", writers), |b| {
+            let stop = AtomicBool::new(false);
+            thread::scope(|s| {
+                for writer in 0..writers {
+                    let tree = tree.clone();
+                    let stop = &stop;
+                    s.spawn(move || {
+                        let client_id = ClientId::MAX - writer;
+                        let topic_filter = filter(&format!("churn/writer{writer}/#"));
+                        while !stop.load(Ordering::Relaxed) {
+                            tree.add_subscription(topic_filter.clone(), client_id, QoS::Level0)
+                                .unwrap();
+                            tree.remove_client(client_id).unwrap();
+                        }
+                    });
+                }
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 1) % topics.len();
+                    tree.get_subscriptions(&topics[i])
+                });
+                stop.store(true, Ordering::Relaxed);
+            });
+        });
+    }
+    for readers in [0, 2, 4] {
+        group.bench_function(BenchmarkId::new("write_while_reading", readers), |b| {
+            let stop = AtomicBool::new(false);
+            thread::scope(|s| {
+                for reader in 0..readers {
+                    let tree = tree.clone();
+                    let (stop, topics) = (&stop, &topics);
+                    s.spawn(move || {
+                        let mut i = reader;
+                        while !stop.load(Ordering::Relaxed) {
+                            i = (i + 1) % topics.len();
+                            black_box(tree.get_subscriptions(&topics[i]));
+                        }
+                    });
+                }
+                let topic_filter = filter("churn/+/status");
+                b.iter(|| {
+                    tree.add_subscription(topic_filter.clone(), ClientId::MAX, QoS::Level0)
+                        .unwrap();
+                    tree.remove_subscription(topic_filter.clone(), ClientId::MAX).unwrap();
+                });
+                stop.store(true, Ordering::Relaxed);
+            });
+        });
+    }
+    group.finish();
+}
+
+/// Clients that subscribe and unsubscribe on a tree that is already filled
+fn churn(c: &mut Criterion) {
+    let workload = generators::iot(10_000, 6);
+    let tree = build_tree(&workload);
+    let session: Vec<TopicFilter> = workload
+        .subscriptions
+        .iter()
+        .step_by(workload.subscriptions.len() / 10)
+        .map(|(x, _)| filter(x))
+        .collect();
+    let client_id = ClientId::MAX;
+    let mut group = c.benchmark_group("churn");
+
+    group.throughput(Throughput::Elements(2));
+    let mut topic_tree = tree.clone();
+    group.bench_function(BenchmarkId::new("subscribe_unsubscribe", "TopicTree"), |b| {
+        b.iter(|| {
+            topic_tree.add_subscription(session[0].clone(), client_id, QoS::Level0).unwrap();
+            topic_tree.remove_subscription(session[0].clone(), client_id)
+        })
+    });
+    let mqtt_tree = MqttTopicTree::from_topic_tree(tree.clone());
+    group.bench_function(BenchmarkId::new("subscribe_unsubscribe", "MqttTopicTree"), |b| {
+        b.iter(|| {
+            mqtt_tree.add_subscription(session[0].clone(), client_id, QoS::Level0).unwrap();
+            mqtt_tree.remove_subscription(session[0].clone(), client_id).unwrap()
+        })
+    });
+    let sharded = ShardedMqttTopicTree::default();
+    for (topic_filter, client_id) in workload.subscriptions.iter() {
+        sharded.add_subscription(filter(topic_filter), *client_id, QoS::Level1).unwrap();
+    }
+    group.bench_function(BenchmarkId::new("subscribe_unsubscribe", "ShardedMqttTopicTree"), |b| {
+        b.iter(|| {
+            sharded.add_subscription(session[0].clone(), client_id, QoS::Level0).unwrap();
+            sharded.remove_subscription(session[0].clone(), client_id).unwrap()
+        })
+    });
+
+    // A client connects with its subscriptions and disconnects again
+    group.throughput(Throughput::Elements(session.len() as u64 + 1));
+    group.bench_function(BenchmarkId::new("session", "TopicTree"), |b| {
+        b.iter(|| {
+            for topic_filter in session.iter() {
+                topic_tree.add_subscription(topic_filter.clone(), client_id, QoS::Level0).unwrap();
+            }
+            topic_tree.remove_client(client_id)
+        })
+    });
+    group.bench_function(BenchmarkId::new("session", "MqttTopicTree"), |b| {
+        b.iter(|| {
+            let mut batch = mqtt_tree.batch();
+            for topic_filter in session.iter() {
+                batch.add_subscription(topic_filter.clone(), client_id, QoS::Level0).unwrap();
+            }
+            batch.commit().unwrap();
+            mqtt_tree.remove_client(client_id).unwrap()
+        })
+    });
+
+    // Many clients reconnect at once, their subscriptions are committed in one batch
+    group.throughput(Throughput::Elements(1_000));
+    group.bench_function(BenchmarkId::new("reconnect_storm", "MqttTopicTree"), |b| {
+        b.iter_batched(
+            || MqttTopicTree::from_topic_tree(tree.clone()),
+            |mqtt_tree| {
+                let mut batch = mqtt_tree.batch();
+                for i in 0..1_000 {
+                    let topic_filter = session[i % session.len()].clone();
+                    batch.add_subscription(topic_filter, client_id - i as ClientId, QoS::Level0)
+                        .unwrap();
+                }
+                batch.commit().unwrap();
+                mqtt_tree
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    topic_shapes,
+    topic_sets,
+    deep_trees,
+    wildcard_heavy,
+    fan_out,
+    shared_groups,
+    contention,
+    churn
+);
+criterion_main!(benches);
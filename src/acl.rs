@@ -0,0 +1,350 @@
+use crate::topic::TopicFilterError;
+use crate::{TopicFilter, TopicName};
+use std::collections::HashMap;
+
+const PUBLISH: u8 = 1;
+const SUBSCRIBE: u8 = 2;
+
+/// Who a rule applies to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Principal {
+    All,
+    User(String),
+    Client(String),
+    Group(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclAction {
+    Publish,
+    Subscribe,
+    PublishSubscribe,
+}
+
+impl AclAction {
+    fn bits(self) -> u8 {
+        match self {
+            AclAction::Publish => PUBLISH,
+            AclAction::Subscribe => SUBSCRIBE,
+            AclAction::PublishSubscribe => PUBLISH | SUBSCRIBE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AclPermission {
+    Allow,
+    #[default]
+    Deny,
+}
+
+/// The connection a request is authorized for. `client_identifier` is the identifier the client
+/// sent when connecting, and is what `%c` is replaced with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub username: Option<String>,
+    pub client_identifier: String,
+    pub groups: Vec<String>,
+}
+
+/// The Acl stores allow and deny rules for publishing and subscribing in a wildcard tree per
+/// principal, so a request is checked with a single walk per principal, the same way a publish is
+/// routed.
+///
+/// A level of a rule can contain `%u` and `%c`, which are replaced with the username and the
+/// client identifier of the request. A deny rule always wins over an allow rule, and requests that
+/// match no rule get the default permission.
+#[derive(Default, Debug, Clone)]
+pub struct Acl {
+    default_permission: AclPermission,
+    principals: HashMap<Principal, AclNode>,
+}
+
+impl Acl {
+    pub fn new(default_permission: AclPermission) -> Self {
+        Self {
+            default_permission,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a rule, the pattern is a topic filter that may contain `%u` and `%c`. Shared
+    /// subscriptions can't be used as a pattern.
+    pub fn add_rule(
+        &mut self,
+        principal: Principal,
+        action: AclAction,
+        permission: AclPermission,
+        pattern: &str,
+    ) -> Result<(), TopicFilterError> {
+        let topic_filter = TopicFilter::try_from(pattern)?;
+        topic_filter.ensure_unshared()?;
+        let mut curr_node = self.principals.entry(principal).or_default();
+        for i in 0..topic_filter.levels() {
+            let topic_level = topic_filter.get_part(i).unwrap();
+            match topic_level {
+                "+" => {
+                    curr_node = curr_node.single_level_wildcard.get_or_insert_default();
+                }
+                "#" => {
+                    let entry = curr_node.multi_level_wildcard.get_or_insert_default();
+                    entry.add(action, permission);
+                    return Ok(());
+                }
+                _ if topic_level.contains("%u") || topic_level.contains("%c") => {
+                    let idx = match curr_node
+                        .pattern_nodes
+                        .iter()
+                        .position(|(x, _)| x == topic_level)
+                    {
+                        Some(idx) => idx,
+                        None => {
+                            curr_node
+                                .pattern_nodes
+                                .push((topic_level.to_owned(), AclNode::default()));
+                            curr_node.pattern_nodes.len() - 1
+                        }
+                    };
+                    curr_node = &mut curr_node.pattern_nodes[idx].1;
+                }
+                _ => {
+                    curr_node = curr_node.sub_nodes.entry(topic_level.to_owned()).or_default();
+                }
+            }
+        }
+        curr_node.content.add(action, permission);
+        Ok(())
+    }
+
+    /// Checks whether the identity may publish to the topic
+    pub fn authorize_publish(&self, identity: &Identity, topic_name: &TopicName) -> bool {
+        let mut found = AclEntry::default();
+        for node in self.nodes(identity) {
+            node.matching(topic_name, 0, identity, &mut found);
+        }
+        self.decide(found, PUBLISH)
+    }
+
+    /// Checks whether the identity may subscribe to the filter. The filter is only allowed if it is
+    /// a subset of an allowed pattern, and is rejected if it overlaps a denied pattern at all. The
+    /// `$share/<group>/` prefix of a shared subscription is ignored.
+    pub fn authorize_subscribe(&self, identity: &Identity, topic_filter: &TopicFilter) -> bool {
+        let mut covering = AclEntry::default();
+        let mut overlapping = AclEntry::default();
+        for node in self.nodes(identity) {
+            node.covering(topic_filter, 0, identity, &mut covering);
+            node.overlapping(topic_filter, 0, identity, &mut overlapping);
+        }
+        if overlapping.deny & SUBSCRIBE != 0 {
+            return false;
+        }
+        self.decide(covering, SUBSCRIBE)
+    }
+
+    fn decide(&self, found: AclEntry, action: u8) -> bool {
+        if found.deny & action != 0 {
+            return false;
+        }
+        if found.allow & action != 0 {
+            return true;
+        }
+        self.default_permission == AclPermission::Allow
+    }
+
+    /// The trees of all principals that apply to the identity
+    fn nodes<'a>(&'a self, identity: &'a Identity) -> impl Iterator<Item = &'a AclNode> {
+        let user = identity.username.as_ref().map(|x| Principal::User(x.clone()));
+        let groups = identity.groups.iter().map(|x| Principal::Group(x.clone()));
+        [Principal::All, Principal::Client(identity.client_identifier.clone())]
+            .into_iter()
+            .chain(user)
+            .chain(groups)
+            .filter_map(|x| self.principals.get(&x))
+    }
+}
+
+/// The actions that are allowed and denied by the rules stored at one place in the tree
+#[derive(Default, Debug, Clone, Copy)]
+struct AclEntry {
+    allow: u8,
+    deny: u8,
+}
+
+impl AclEntry {
+    fn add(&mut self, action: AclAction, permission: AclPermission) {
+        match permission {
+            AclPermission::Allow => self.allow |= action.bits(),
+            AclPermission::Deny => self.deny |= action.bits(),
+        }
+    }
+
+    fn merge(&mut self, other: &AclEntry) {
+        self.allow |= other.allow;
+        self.deny |= other.deny;
+    }
+}
+
+/// The AclNode has the same layout as the TopicNode, levels with a placeholder are kept apart
+/// because they can only be compared once the identity is known
+#[derive(Default, Debug, Clone)]
+struct AclNode {
+    multi_level_wildcard: Option<AclEntry>,
+    single_level_wildcard: Option<Box<AclNode>>,
+    sub_nodes: HashMap<String, AclNode>,
+    pattern_nodes: Vec<(String, AclNode)>,
+    content: AclEntry,
+}
+
+impl AclNode {
+    /// Merges the rules that match the topic
+    fn matching(
+        &self,
+        topic_name: &TopicName,
+        level: usize,
+        identity: &Identity,
+        found: &mut AclEntry,
+    ) {
+        let skip_wildcards = level == 0
+            && topic_name.get_part(0).is_some_and(|x| x.starts_with('$'));
+        if !skip_wildcards && let Some(entry) = self.multi_level_wildcard.as_ref() {
+            found.merge(entry);
+        }
+        let Some(topic_level) = topic_name.get_part(level) else {
+            found.merge(&self.content);
+            return;
+        };
+        if !skip_wildcards && let Some(node) = self.single_level_wildcard.as_deref() {
+            node.matching(topic_name, level + 1, identity, found);
+        }
+        if let Some(node) = self.sub_nodes.get(topic_level) {
+            node.matching(topic_name, level + 1, identity, found);
+        }
+        for node in self.pattern_nodes_matching(topic_level, identity) {
+            node.matching(topic_name, level + 1, identity, found);
+        }
+    }
+
+    /// Merges the rules that the filter is a subset of
+    fn covering(
+        &self,
+        topic_filter: &TopicFilter,
+        level: usize,
+        identity: &Identity,
+        found: &mut AclEntry,
+    ) {
+        let topic_level = topic_filter.get_part(level);
+        let dollar = level == 0 && topic_level.is_some_and(|x| x.starts_with('$'));
+        if !dollar && let Some(entry) = self.multi_level_wildcard.as_ref() {
+            found.merge(entry);
+        }
+        match topic_level {
+            None => found.merge(&self.content),
+            // Only a multi level wildcard covers a multi level wildcard, and `+/#` in the first
+            // level since there is no empty parent level for `#` to match there
+            Some("#") => {
+                if level == 0
+                    && let Some(node) = self.single_level_wildcard.as_deref()
+                    && let Some(entry) = node.multi_level_wildcard.as_ref()
+                {
+                    found.merge(entry);
+                }
+            }
+            Some("+") => {
+                if let Some(node) = self.single_level_wildcard.as_deref() {
+                    node.covering(topic_filter, level + 1, identity, found);
+                }
+            }
+            Some(topic_level) => {
+                if !dollar && let Some(node) = self.single_level_wildcard.as_deref() {
+                    node.covering(topic_filter, level + 1, identity, found);
+                }
+                if let Some(node) = self.sub_nodes.get(topic_level) {
+                    node.covering(topic_filter, level + 1, identity, found);
+                }
+                for node in self.pattern_nodes_matching(topic_level, identity) {
+                    node.covering(topic_filter, level + 1, identity, found);
+                }
+            }
+        }
+    }
+
+    /// Merges the rules that match at least one topic that the filter matches as well
+    fn overlapping(
+        &self,
+        topic_filter: &TopicFilter,
+        level: usize,
+        identity: &Identity,
+        found: &mut AclEntry,
+    ) {
+        let topic_level = topic_filter.get_part(level);
+        let dollar = level == 0 && topic_level.is_some_and(|x| x.starts_with('$'));
+        if !dollar && let Some(entry) = self.multi_level_wildcard.as_ref() {
+            found.merge(entry);
+        }
+        match topic_level {
+            None => found.merge(&self.content),
+            Some("#") => self.merge_all(level == 0, found),
+            Some("+") => {
+                if let Some(node) = self.single_level_wildcard.as_deref() {
+                    node.overlapping(topic_filter, level + 1, identity, found);
+                }
+                for (sub_level, node) in self.sub_nodes.iter() {
+                    if !(level == 0 && sub_level.starts_with('$')) {
+                        node.overlapping(topic_filter, level + 1, identity, found);
+                    }
+                }
+                for (_, node) in self.pattern_nodes.iter() {
+                    node.overlapping(topic_filter, level + 1, identity, found);
+                }
+            }
+            Some(topic_level) => {
+                if !dollar && let Some(node) = self.single_level_wildcard.as_deref() {
+                    node.overlapping(topic_filter, level + 1, identity, found);
+                }
+                if let Some(node) = self.sub_nodes.get(topic_level) {
+                    node.overlapping(topic_filter, level + 1, identity, found);
+                }
+                for node in self.pattern_nodes_matching(topic_level, identity) {
+                    node.overlapping(topic_filter, level + 1, identity, found);
+                }
+            }
+        }
+    }
+
+    /// Merges all rules in this node and the nodes below it
+    fn merge_all(&self, skip_dollar: bool, found: &mut AclEntry) {
+        found.merge(&self.content);
+        if let Some(entry) = self.multi_level_wildcard.as_ref() {
+            found.merge(entry);
+        }
+        if let Some(node) = self.single_level_wildcard.as_deref() {
+            node.merge_all(false, found);
+        }
+        for (sub_level, node) in self.sub_nodes.iter() {
+            if !(skip_dollar && sub_level.starts_with('$')) {
+                node.merge_all(false, found);
+            }
+        }
+        for (_, node) in self.pattern_nodes.iter() {
+            node.merge_all(false, found);
+        }
+    }
+
+    /// The nodes with a placeholder that equal the level once the identity is filled in
+    fn pattern_nodes_matching<'a>(
+        &'a self,
+        topic_level: &'a str,
+        identity: &'a Identity,
+    ) -> impl Iterator<Item = &'a AclNode> {
+        self.pattern_nodes.iter().filter_map(move |(pattern, node)| {
+            let username = identity.username.as_deref();
+            if pattern.contains("%u") && username.is_none() {
+                return None;
+            }
+            let level = pattern
+                .replace("%u", username.unwrap_or_default())
+                .replace("%c", &identity.client_identifier);
+            (level == topic_level).then_some(node)
+        })
+    }
+}
@@ -0,0 +1,165 @@
+//! An async handle to a MqttTopicTree for brokers that run on tokio.
+//!
+//! Writing to a MqttTopicTree blocks on the write lock and on the readers leaving the old copy of
+//! the tree, which would stall an executor thread. The AsyncMqttTopicTree sends its writes to a
+//! dedicated writer thread instead, which commits all writes that are waiting as one batch, so the
+//! cost of publishing is shared between them. Reads go to the tree directly and never block.
+//!
+//! The writes wait in a bounded queue, once it is full a write waits for room before it is queued,
+//! so a writer that falls behind slows the tasks that write down instead of buffering without
+//! limit. The writer runs on its own thread rather than with `spawn_blocking`: it lives as long as
+//! the tree and would hold one thread of the blocking pool for all of that time, and a thread can
+//! be started outside of a runtime.
+
+use crate::sync::{Epoch, TopicTreeOperations};
+use crate::sync::TopicTreeOperations::{AddSubscription, RemoveClient, RemoveSubscription};
+use crate::{ClientId, MqttTopicTree, QoS, Subscriber, TopicFilter, TopicName, WriteError};
+use std::io;
+use tokio::sync::{mpsc, oneshot};
+
+/// The most writes that are committed together
+const MAX_BATCH: usize = 256;
+/// The number of writes that can wait for the writer before a write has to wait for room
+const QUEUE_CAPACITY: usize = 4 * MAX_BATCH;
+
+struct WriteRequest {
+    operation: TopicTreeOperations,
+    done: oneshot::Sender<Result<Epoch, WriteError>>,
+}
+
+#[derive(Clone)]
+pub struct AsyncMqttTopicTree {
+    tree: MqttTopicTree,
+    sender: mpsc::Sender<WriteRequest>,
+}
+
+impl AsyncMqttTopicTree {
+    /// Starts the writer thread of the tree, it stops once every clone of the handle is dropped
+    pub fn new(tree: MqttTopicTree) -> io::Result<Self> {
+        Self::with_capacity(tree, QUEUE_CAPACITY)
+    }
+
+    /// Like [`new`](Self::new), with room for `capacity` writes in the queue of the writer. Panics
+    /// if the capacity is zero.
+    pub fn with_capacity(tree: MqttTopicTree, capacity: usize) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let writer_tree = tree.clone();
+        std::thread::Builder::new()
+            .name("mqtt-topic-tree-writer".to_owned())
+            .spawn(move || run_writer(writer_tree, receiver))?;
+        Ok(Self { tree, sender })
+    }
+
+    /// The tree the writes are applied to, writing to it directly blocks the calling thread
+    pub fn tree(&self) -> &MqttTopicTree {
+        &self.tree
+    }
+
+    /// Queues the subscription, the returned future resolves with its epoch once it is visible to
+    /// readers. Writes are queued when their future is first polled, waiting for room in the queue
+    /// if it is full, so writes from one task are applied in the order they are awaited.
+    pub fn add_subscription(
+        &self,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+        qos: QoS,
+    ) -> impl Future<Output = Result<Epoch, WriteError>> + Send + 'static {
+        self.write(AddSubscription(topic_filter, client_id, qos))
+    }
+
+    pub fn remove_subscription(
+        &self,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+    ) -> impl Future<Output = Result<Epoch, WriteError>> + Send + 'static {
+        self.write(RemoveSubscription(topic_filter, client_id))
+    }
+
+    pub fn remove_client(
+        &self,
+        client_id: ClientId,
+    ) -> impl Future<Output = Result<Epoch, WriteError>> + Send + 'static {
+        self.write(RemoveClient(client_id))
+    }
+
+    pub fn get_subscriptions(&self, publish_topic: &TopicName) -> Vec<Subscriber> {
+        self.tree.get_subscriptions(publish_topic)
+    }
+
+    /// The handle is not Sync, so the future must not borrow it to be Send
+    fn write(
+        &self,
+        operation: TopicTreeOperations,
+    ) -> impl Future<Output = Result<Epoch, WriteError>> + Send + 'static {
+        let sender = self.sender.clone();
+        async move {
+            let (done, result) = oneshot::channel();
+            sender
+                .send(WriteRequest { operation, done })
+                .await
+                .map_err(|_| WriteError::Closed)?;
+            result.await.map_err(|_| WriteError::Closed)?
+        }
+    }
+}
+
+fn run_writer(tree: MqttTopicTree, mut receiver: mpsc::Receiver<WriteRequest>) {
+    let mut next = receiver.blocking_recv();
+    while next.is_some() {
+        let mut batch = tree.batch();
+        let mut waiting = Vec::new();
+        let mut has_removals = false;
+        while let Some(WriteRequest { operation, done }) = next.take() {
+            match operation {
+                // Removals in a batch don't free up quota, so they are committed first
+                AddSubscription(..) if has_removals => {
+                    next = Some(WriteRequest { operation, done });
+                    break;
+                }
+                AddSubscription(topic_filter, client_id, qos) => {
+                    match batch.add_subscription(topic_filter, client_id, qos) {
+                        Ok(()) => waiting.push(done),
+                        Err(e) => {
+                            let _ = done.send(Err(e.into()));
+                        }
+                    }
+                }
+                RemoveSubscription(topic_filter, client_id) => {
+                    batch.remove_subscription(topic_filter, client_id);
+                    has_removals = true;
+                    waiting.push(done);
+                }
+                RemoveClient(client_id) => {
+                    batch.remove_client(client_id);
+                    has_removals = true;
+                    waiting.push(done);
+                }
+            }
+            if waiting.len() < MAX_BATCH {
+                next = receiver.try_recv().ok();
+            }
+        }
+        match batch.commit() {
+            Ok(epoch) => {
+                for done in waiting {
+                    let _ = done.send(Ok(epoch));
+                }
+            }
+            Err(e) => {
+                for done in waiting {
+                    let e = match &e {
+                        WriteError::Log(e) => {
+                            WriteError::Log(io::Error::new(e.kind(), e.to_string()))
+                        }
+                        WriteError::Rejected(e) => WriteError::Rejected(e.clone()),
+                        WriteError::Closed => WriteError::Closed,
+                    };
+                    let _ = done.send(Err(e));
+                }
+            }
+        }
+        if next.is_none() {
+            next = receiver.blocking_recv();
+        }
+    }
+}
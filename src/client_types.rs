@@ -1,7 +1,17 @@
+use std::fmt;
+
 /// Packet delivery [Quality of Service] level.
 ///
+/// Levels are ordered, so the effective QoS of a delivery is the `min` of the publish and the
+/// subscription QoS.
+///
 /// [Quality of Service]: http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718099
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "u8", into = "u8")
+)]
 pub enum QoS {
     /// `QoS 0`. At most once. No ack needed.
     Level0 = 0,
@@ -11,6 +21,37 @@ pub enum QoS {
     Level2 = 2,
 }
 
+impl TryFrom<u8> for QoS {
+    type Error = InvalidQoS;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(QoS::Level0),
+            1 => Ok(QoS::Level1),
+            2 => Ok(QoS::Level2),
+            _ => Err(InvalidQoS(value)),
+        }
+    }
+}
+
+impl From<QoS> for u8 {
+    fn from(value: QoS) -> Self {
+        value as u8
+    }
+}
+
+/// A QoS value outside of the 0..=2 range
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidQoS(pub u8);
+
+impl fmt::Display for InvalidQoS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not a valid QoS level", self.0)
+    }
+}
+
+impl std::error::Error for InvalidQoS {}
+
 /// ClientId is the internal id assigned to the client by the server, u64 will never overflow, so we
 /// can safely assume this is unique
 pub type ClientId = u64;
\ No newline at end of file
@@ -0,0 +1,152 @@
+use crate::intern::Interner;
+use crate::topic_tree::TopicNode;
+use crate::{QoS, SubscriptionEvent, TopicFilter, TopicName};
+use std::collections::HashMap;
+
+/// Identifies a broker node in a cluster
+pub type NodeId = u64;
+
+/// A change to the routes of a node, as it is exchanged between the nodes of a cluster
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteUpdate {
+    Add(TopicFilter, NodeId),
+    Remove(TopicFilter, NodeId),
+    /// The node left the cluster, all of its routes are removed
+    RemoveNode(NodeId),
+}
+
+impl RouteUpdate {
+    /// The update the local node has to send to its peers for an event of its own TopicTree, only
+    /// filters that are activated or deactivated change the routes
+    pub fn from_event(event: &SubscriptionEvent, node_id: NodeId) -> Option<RouteUpdate> {
+        match event {
+            SubscriptionEvent::FilterActivated(topic_filter) => {
+                Some(RouteUpdate::Add(topic_filter.clone(), node_id))
+            }
+            SubscriptionEvent::FilterDeactivated(topic_filter) => {
+                Some(RouteUpdate::Remove(topic_filter.clone(), node_id))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A node that has to receive a publish, together with the shared filters it was picked for. The
+/// node delivers the publish with
+/// [`TopicTree::get_routed_subscriptions`](crate::TopicTree::get_routed_subscriptions).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub node_id: NodeId,
+    pub shared_filters: Vec<TopicFilter>,
+}
+
+/// The ClusterRoutes store which nodes of a cluster have subscribers for a filter, rather than
+/// which clients. Every node keeps a reference count per filter, and is only routed to while the
+/// count is above zero. The nodes are stored in the same TopicNode structure as the clients of a
+/// TopicTree, so the matching rules are the same.
+///
+/// A shared group that has members on several nodes is routed to exactly one of those nodes for
+/// each publish, and only that node delivers it to one of its members.
+#[derive(Default, Debug, Clone)]
+pub struct ClusterRoutes {
+    root_node: TopicNode,
+    interner: Interner,
+    ref_counts: HashMap<(TopicFilter, NodeId), usize>,
+}
+
+impl ClusterRoutes {
+    /// Adds a reference from the node to the filter, returns whether it is the first one
+    pub fn add_route(&mut self, topic_filter: TopicFilter, node_id: NodeId) -> bool {
+        let ref_count = self
+            .ref_counts
+            .entry((topic_filter.clone(), node_id))
+            .or_insert(0);
+        *ref_count += 1;
+        if *ref_count > 1 {
+            return false;
+        }
+        // The QoS is decided by the subscriptions on the node itself
+        self.root_node
+            .add_subscriber(&mut self.interner, topic_filter, node_id, QoS::Level0);
+        true
+    }
+
+    /// Removes a reference from the node to the filter, returns whether it was the last one
+    pub fn remove_route(&mut self, topic_filter: &TopicFilter, node_id: NodeId) -> bool {
+        let key = (topic_filter.clone(), node_id);
+        let Some(ref_count) = self.ref_counts.get_mut(&key) else {
+            return false;
+        };
+        *ref_count -= 1;
+        if *ref_count > 0 {
+            return false;
+        }
+        self.ref_counts.remove(&key);
+        self.root_node
+            .remove_subscriber(&mut self.interner, topic_filter, 0, node_id);
+        true
+    }
+
+    /// Removes all routes of the node, returns the number of filters it was routed for
+    pub fn remove_node(&mut self, node_id: NodeId) -> usize {
+        self.ref_counts.retain(|(_, x), _| *x != node_id);
+        self.root_node.remove_client(&mut self.interner, node_id).0
+    }
+
+    /// Merges an update received from a peer
+    pub fn apply(&mut self, update: RouteUpdate) {
+        match update {
+            RouteUpdate::Add(topic_filter, node_id) => {
+                self.add_route(topic_filter, node_id);
+            }
+            RouteUpdate::Remove(topic_filter, node_id) => {
+                self.remove_route(&topic_filter, node_id);
+            }
+            RouteUpdate::RemoveNode(node_id) => {
+                self.remove_node(node_id);
+            }
+        }
+    }
+
+    /// The number of references the node holds to the filter
+    pub fn ref_count(&self, topic_filter: &TopicFilter, node_id: NodeId) -> usize {
+        self.ref_counts
+            .get(&(topic_filter.clone(), node_id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// The number of filters that are routed to a node, counted once per node
+    pub fn len(&self) -> usize {
+        self.ref_counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ref_counts.is_empty()
+    }
+
+    /// The nodes that must receive a publish on the topic, every node is listed once. For each
+    /// matching shared group one of the nodes with members is picked at random.
+    pub fn route(&self, publish_topic: &TopicName) -> Vec<Route> {
+        let mut routes: HashMap<NodeId, Vec<TopicFilter>> = HashMap::new();
+        let symbols = &self.interner;
+        self.root_node
+            .visit_matches(symbols, publish_topic, 0, &mut Vec::new(), &mut |levels, sub_info| {
+                for node_id in sub_info.client_subscriptions.keys() {
+                    routes.entry(*node_id).or_default();
+                }
+                for group in sub_info.shared_subscriptions.iter() {
+                    let node_id = group.get_next_client().client_id;
+                    let shared_filter = format!("$share/{}/{}", group.group_id, levels.join("/"));
+                    let shared_filter = TopicFilter::try_from(shared_filter).unwrap();
+                    routes.entry(node_id).or_default().push(shared_filter);
+                }
+            });
+        let mut routes: Vec<Route> = routes
+            .into_iter()
+            .map(|(node_id, shared_filters)| Route { node_id, shared_filters })
+            .collect();
+        routes.sort_by_key(|x| x.node_id);
+        routes
+    }
+}
@@ -0,0 +1,34 @@
+use crate::{ClientId, QoS, TopicFilter};
+use std::fmt;
+use std::sync::Arc;
+
+/// A change to the subscriptions of a TopicTree. Filters include their `$share/<group>/` prefix, a
+/// shared filter is active as long as its group has at least one member.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubscriptionEvent {
+    /// The filter gained its first subscriber
+    FilterActivated(TopicFilter),
+    /// The filter lost its last subscriber
+    FilterDeactivated(TopicFilter),
+    /// A client subscribed to a filter, or changed the QoS of an existing subscription
+    ClientSubscribed {
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+        qos: QoS,
+    },
+    /// A client unsubscribed from a filter
+    ClientUnsubscribed {
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+    },
+}
+
+/// A callback that is called for every SubscriptionEvent while the tree applies an operation
+#[derive(Clone)]
+pub(crate) struct SubscriptionListener(pub(crate) Arc<dyn Fn(&SubscriptionEvent) + Send + Sync>);
+
+impl fmt::Debug for SubscriptionListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SubscriptionListener")
+    }
+}
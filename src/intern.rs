@@ -0,0 +1,320 @@
+//! Interning of the levels the nodes of a TopicTree are keyed by.
+//!
+//! With the `intern` feature a node stores a [`Symbol`] for each of its sub levels instead of a
+//! String. The names of the symbols live in a table that is shared by every clone of the tree, so
+//! the two copies of a MqttTopicTree store every distinct level once. The table is persistent:
+//! each copy holds its own version of it, which shares its storage with the other versions and is
+//! replaced when the copy interns or releases a level, so a lookup reads it without any locking.
+//! A lookup resolves each level of the topic to a symbol once and then only compares symbols.
+//!
+//! Every symbol counts the nodes that are keyed by it in each copy, and is removed from the table
+//! once none of the copies uses it anymore. The table never holds more levels than the copies
+//! hold nodes. Without the feature the Interner is empty and the levels are Strings.
+//!
+//! Maps keyed by the name of a level use SipHash, which resists collision attacks but takes most
+//! of the time of a lookup for short levels. The `fast-hash` feature replaces it with foldhash.
+//! The hasher is chosen for the whole build rather than per tree: a feature is additive, so a
+//! dependency that enables `fast-hash` switches every tree in the build to foldhash, and a tree
+//! that must resist collision attacks from untrusted topics needs a build without the feature.
+//! With `intern` the hasher only applies to the table of names, the nodes are keyed by symbols.
+
+/// The hasher of the maps that are keyed by the name of a level, fixed by the `fast-hash` feature
+#[cfg(not(feature = "fast-hash"))]
+pub(crate) type LevelHasher = std::hash::RandomState;
+#[cfg(feature = "fast-hash")]
+pub(crate) type LevelHasher = foldhash::fast::RandomState;
+
+#[cfg(feature = "intern")]
+pub(crate) use self::interned::*;
+#[cfg(not(feature = "intern"))]
+pub(crate) use self::plain::*;
+
+/// Removes the node keyed by the level and returns its key
+pub(crate) fn remove_level<V: Clone>(
+    sub_nodes: &mut LevelMap<V>,
+    level: LevelRef,
+) -> Option<(Level, V)> {
+    #[cfg(not(feature = "rcu"))]
+    return sub_nodes.remove_entry(level);
+    #[cfg(feature = "rcu")]
+    return sub_nodes.remove_with_key(level);
+}
+
+#[cfg(not(feature = "intern"))]
+mod plain {
+    use super::LevelHasher;
+    use crate::topic_tree::NodeMap;
+
+    /// The key of a literal level in a TopicNode
+    pub(crate) type Level = String;
+    /// The sub nodes of a TopicNode
+    pub(crate) type LevelMap<V> = NodeMap<Level, V, LevelHasher>;
+    /// A resolved level that the sub nodes of a TopicNode can be indexed with
+    pub(crate) type LevelRef<'a> = &'a str;
+
+    #[derive(Clone, Debug, Default)]
+    pub(crate) struct Interner;
+
+    impl Interner {
+        /// Returns the key of a new node, every call must be matched by a call to
+        /// [`release`](Self::release) once the node is pruned
+        pub(crate) fn intern(&mut self, level: impl Into<String>) -> Level {
+            level.into()
+        }
+
+        pub(crate) fn release(&mut self, _level: &Level) {}
+
+        /// Returns None if no node is keyed by the level
+        #[inline]
+        pub(crate) fn get<'a>(&'a self, level: &'a str) -> Option<LevelRef<'a>> {
+            Some(level)
+        }
+
+        pub(crate) fn name<'a>(&'a self, level: &'a Level) -> &'a str {
+            level
+        }
+
+        #[cfg(feature = "rcu")]
+        pub(crate) fn frozen(&self) -> Self {
+            Self
+        }
+
+        /// The memory used by the table, the levels are counted in the nodes instead
+        pub(crate) fn estimated_memory(&self) -> usize {
+            0
+        }
+    }
+
+    /// The heap memory used by a single key
+    pub(crate) fn level_memory(level: &Level) -> usize {
+        level.capacity()
+    }
+}
+
+#[cfg(feature = "intern")]
+mod interned {
+    use super::LevelHasher;
+    use crate::topic_tree::NodeMap;
+    use imbl::shared_ptr::DefaultSharedPtr;
+    use imbl::{GenericHashMap, Vector};
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::hash::{BuildHasherDefault, Hasher};
+    use std::mem::size_of;
+    use std::sync::Arc;
+
+    /// A level that was added to the table of an Interner
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub(crate) struct Symbol(u32);
+
+    pub(crate) type Level = Symbol;
+    pub(crate) type LevelRef<'a> = &'a Symbol;
+    pub(crate) type LevelMap<V> = NodeMap<Level, V, BuildHasherDefault<SymbolHasher>>;
+
+    /// Symbols are handed out in order, so multiplying them spreads them over the whole range
+    /// without the cost of a keyed hash
+    #[derive(Default)]
+    pub(crate) struct SymbolHasher(u64);
+
+    impl Hasher for SymbolHasher {
+        fn write(&mut self, bytes: &[u8]) {
+            for byte in bytes {
+                self.write_u32(*byte as u32);
+            }
+        }
+
+        fn write_u32(&mut self, i: u32) {
+            self.0 = (self.0 ^ i as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        }
+
+        fn finish(&self) -> u64 {
+            self.0
+        }
+    }
+
+    /// A version of the table, cloning it is cheap and the clone shares all of its storage
+    #[derive(Clone, Default)]
+    struct Table {
+        symbols: GenericHashMap<Arc<str>, Symbol, LevelHasher, DefaultSharedPtr>,
+        /// Indexed by symbol, released symbols are None until they are handed out again
+        names: Vector<Option<Arc<str>>>,
+    }
+
+    /// The state the copies share, only writers lock it
+    #[derive(Default)]
+    struct Shared {
+        latest: Table,
+        /// The number of nodes keyed by each symbol, per copy
+        refs: HashMap<u64, Vec<usize>>,
+        next_copy: u64,
+        free: Vec<Symbol>,
+    }
+
+    impl Shared {
+        fn new_copy(&mut self, refs: Vec<usize>) -> u64 {
+            let copy = self.next_copy;
+            self.next_copy += 1;
+            self.refs.insert(copy, refs);
+            copy
+        }
+
+        /// Removes the symbol if no copy has a node keyed by it anymore
+        fn remove_unused(&mut self, symbol: Symbol) {
+            let index = symbol.0 as usize;
+            if self.refs.values().any(|x| x.get(index).is_some_and(|x| *x > 0)) {
+                return;
+            }
+            let name = self.latest.names.set(index, None).unwrap();
+            self.latest.symbols.remove(&*name);
+            self.free.push(symbol);
+        }
+    }
+
+    /// The symbol table of one copy of a tree. Clones share the table, each copy reads its own
+    /// version of it and only writers lock the shared part.
+    pub(crate) struct Interner {
+        table: Table,
+        shared: Arc<Mutex<Shared>>,
+        /// None for a frozen table, which holds no references and can't change
+        copy: Option<u64>,
+    }
+
+    impl Default for Interner {
+        fn default() -> Self {
+            let mut shared = Shared::default();
+            let copy = shared.new_copy(Vec::new());
+            Self {
+                table: Table::default(),
+                shared: Arc::new(Mutex::new(shared)),
+                copy: Some(copy),
+            }
+        }
+    }
+
+    impl Clone for Interner {
+        fn clone(&self) -> Self {
+            let Some(copy) = self.copy else {
+                return self.frozen();
+            };
+            let mut shared = self.shared.lock();
+            let refs = shared.refs[&copy].clone();
+            let copy = shared.new_copy(refs);
+            Self {
+                table: self.table.clone(),
+                shared: self.shared.clone(),
+                copy: Some(copy),
+            }
+        }
+    }
+
+    impl Drop for Interner {
+        fn drop(&mut self) {
+            let Some(copy) = self.copy else {
+                return;
+            };
+            let mut shared = self.shared.lock();
+            let refs = shared.refs.remove(&copy).unwrap_or_default();
+            for (index, refs) in refs.into_iter().enumerate() {
+                if refs > 0 {
+                    shared.remove_unused(Symbol(index as u32));
+                }
+            }
+        }
+    }
+
+    impl fmt::Debug for Interner {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Interner")
+                .field("symbols", &self.table.symbols.len())
+                .finish()
+        }
+    }
+
+    impl Interner {
+        /// Returns the key of a new node, every call must be matched by a call to
+        /// [`release`](Self::release) once the node is pruned
+        pub(crate) fn intern(&mut self, level: impl AsRef<str>) -> Level {
+            let level = level.as_ref();
+            let mut shared = self.shared.lock();
+            let shared = &mut *shared;
+            let symbol = match shared.latest.symbols.get(level) {
+                Some(symbol) => *symbol,
+                None => {
+                    let name: Arc<str> = Arc::from(level);
+                    let symbol = match shared.free.pop() {
+                        Some(symbol) => {
+                            shared.latest.names.set(symbol.0 as usize, Some(name.clone()));
+                            symbol
+                        }
+                        None => {
+                            shared.latest.names.push_back(Some(name.clone()));
+                            Symbol(shared.latest.names.len() as u32 - 1)
+                        }
+                    };
+                    shared.latest.symbols.insert(name, symbol);
+                    symbol
+                }
+            };
+            let refs = shared.refs.get_mut(&self.copy.unwrap()).unwrap();
+            let index = symbol.0 as usize;
+            if refs.len() <= index {
+                refs.resize(index + 1, 0);
+            }
+            refs[index] += 1;
+            self.table = shared.latest.clone();
+            symbol
+        }
+
+        /// Drops the reference of a pruned node, the symbol is removed once no node of any copy
+        /// uses it
+        pub(crate) fn release(&mut self, level: &Level) {
+            let mut shared = self.shared.lock();
+            shared.refs.get_mut(&self.copy.unwrap()).unwrap()[level.0 as usize] -= 1;
+            shared.remove_unused(*level);
+            self.table = shared.latest.clone();
+        }
+
+        #[inline]
+        pub(crate) fn get<'a>(&'a self, level: &'a str) -> Option<LevelRef<'a>> {
+            self.table.symbols.get(level)
+        }
+
+        /// A read only version of the table for a copy that never changes. It holds no
+        /// references, a symbol that is released and handed out again afterwards doesn't change
+        /// this version.
+        pub(crate) fn frozen(&self) -> Self {
+            Self {
+                table: self.table.clone(),
+                shared: self.shared.clone(),
+                copy: None,
+            }
+        }
+
+        pub(crate) fn name<'a>(&'a self, level: &'a Level) -> &'a str {
+            self.table.names[level.0 as usize].as_deref().unwrap()
+        }
+
+        /// The number of distinct levels that are used by any copy
+        #[cfg(test)]
+        pub(crate) fn len(&self) -> usize {
+            self.shared.lock().latest.symbols.len()
+        }
+
+        /// Whether both interners store the name of the level in the same place
+        #[cfg(test)]
+        pub(crate) fn shares_name(&self, other: &Interner, level: &Level) -> bool {
+            std::ptr::eq(self.name(level), other.name(level))
+        }
+
+        pub(crate) fn estimated_memory(&self) -> usize {
+            self.table.symbols.len() * (size_of::<(Arc<str>, Symbol)>() + size_of::<usize>())
+                + self.table.names.len() * (size_of::<Option<Arc<str>>>() + size_of::<usize>())
+                + self.table.symbols.keys().map(|x| x.len() + 2 * size_of::<usize>()).sum::<usize>()
+        }
+    }
+
+    pub(crate) fn level_memory(_level: &Level) -> usize {
+        0
+    }
+}
@@ -1,31 +1,88 @@
+pub mod acl;
+#[cfg(feature = "tokio")]
+pub mod async_tree;
+pub mod cluster;
+pub mod events;
+mod intern;
+pub mod limits;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "rcu")]
+mod rcu;
+#[cfg(feature = "serde")]
+mod serialization;
+pub mod sharded;
+pub mod snapshot;
+pub mod stats;
 pub mod sync;
 pub mod topic;
+pub mod topic_index;
 pub mod topic_tree;
+pub mod wal;
 mod client_types;
 
-pub use crate::sync::MqttTopicTree;
+pub use crate::sync::{Batch, Epoch, MqttTopicIndex, MqttTopicTree, WriteError};
+pub use crate::topic_index::TopicIndex;
 pub use crate::topic_tree::{TopicTree, Subscriber};
-pub use crate::topic::{TopicFilter, TopicName};
-pub use crate::client_types::{ClientId, QoS};
+pub use crate::topic::{Strictness, TopicFilter, TopicName};
+pub use crate::client_types::{ClientId, InvalidQoS, QoS};
+#[cfg(feature = "tokio")]
+pub use crate::async_tree::AsyncMqttTopicTree;
+pub use crate::acl::{Acl, AclAction, AclPermission, Identity, Principal};
+pub use crate::cluster::{ClusterRoutes, NodeId, Route, RouteUpdate};
+pub use crate::events::SubscriptionEvent;
+pub use crate::limits::{SubscriptionError, TopicLimits};
+#[cfg(feature = "metrics")]
+pub use crate::metrics::{MatchCounts, MetricsRecorder};
+pub use crate::sharded::{ShardEpochs, ShardedBatch, ShardedMqttTopicTree};
+pub use crate::snapshot::SnapshotError;
+pub use crate::stats::TreeStats;
+pub use crate::wal::RecoveryError;
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
-    use crate::{ClientId, MqttTopicTree, QoS, TopicFilter, TopicName, TopicTree};
+    use std::collections::HashSet;
+    use proptest::prelude::*;
+    use crate::{
+        Acl, AclAction, AclPermission, ClientId, ClusterRoutes, Identity, InvalidQoS,
+        MqttTopicIndex, MqttTopicTree, Principal, QoS, RecoveryError, RouteUpdate, SnapshotError,
+        Strictness, Subscriber, SubscriptionError, SubscriptionEvent, TopicFilter, TopicIndex,
+        TopicLimits, TopicName, TopicTree, TreeStats, WriteError,
+    };
+    use crate::topic::{TopicFilterError, TopicNameError};
+
+    fn filter(x: &str) -> TopicFilter {
+        TopicFilter::try_from(x).unwrap()
+    }
+
+    /// The (client id, qos) pairs of the subscribers, sorted
+    fn sorted(mut x: Vec<Subscriber>) -> Vec<(ClientId, QoS)> {
+        x.sort_by_key(|x| (x.client_id, x.qos));
+        x.iter().map(|x| (x.client_id, x.qos)).collect()
+    }
+
+    /// The entries of an iterator over the subscriptions, with the filters as strings and sorted
+    fn sorted_entries(
+        x: Vec<(TopicFilter, ClientId, QoS, Option<String>)>,
+    ) -> Vec<(String, ClientId, QoS, Option<String>)> {
+        let mut x: Vec<_> = x.into_iter().map(|(a, b, c, d)| (a.to_string(), b, c, d)).collect();
+        x.sort();
+        x
+    }
 
     #[test]
     fn test_add_remove_sub() {
         let mut t = TopicTree::default();
         let s1 = TopicFilter::try_from("home/+/+".to_owned()).unwrap();
-        t.add_subscription(s1, 1, QoS::Level0);
+        t.add_subscription(s1, 1, QoS::Level0).unwrap();
         let s2 = TopicFilter::try_from("home/#".to_owned()).unwrap();
-        t.add_subscription(s2, 2, QoS::Level0);
+        t.add_subscription(s2, 2, QoS::Level0).unwrap();
         let s3 = TopicFilter::try_from("home/+/#".to_owned()).unwrap();
-        t.add_subscription(s3, 3, QoS::Level0);
+        t.add_subscription(s3, 3, QoS::Level0).unwrap();
         let s4 = TopicFilter::try_from("$share/group1/home/bedroom/light".to_owned()).unwrap();
-        t.add_subscription(s4, 4, QoS::Level0);
+        t.add_subscription(s4, 4, QoS::Level0).unwrap();
         let s5 = TopicFilter::try_from("home/bedroom/light".to_owned()).unwrap();
-        t.add_subscription(s5.clone(), 5, QoS::Level0);
+        t.add_subscription(s5.clone(), 5, QoS::Level0).unwrap();
         // Test adding 5 subscribers
         let topic = TopicName::try_from("home/bedroom/light".to_owned()).unwrap();
         let ids = t.get_subscriptions(&topic);
@@ -41,43 +98,1447 @@ mod tests {
     }
 
     #[test]
-    fn speed_test() {
+    fn test_topic_strictness() {
+        // Null is always rejected
+        let res = TopicName::new("home/\0".to_owned(), Strictness::Lenient);
+        assert!(matches!(res, Err(TopicNameError::ContainsNull { .. })));
+        // Control characters and non-characters are only rejected in strict mode
+        for value in ["home/\u{1}", "home/\u{7f}", "home/\u{9f}"] {
+            assert!(TopicName::new(value.to_owned(), Strictness::Lenient).is_ok());
+            let res = TopicName::new(value.to_owned(), Strictness::Strict);
+            assert!(matches!(res, Err(TopicNameError::ContainsControlCharacter { .. })));
+            let res = TopicFilter::new(value.to_owned(), Strictness::Strict);
+            assert!(matches!(res, Err(TopicFilterError::ContainsControlCharacter { .. })));
+        }
+        for value in ["home/\u{fdd0}", "home/\u{fffe}", "home/\u{1ffff}"] {
+            assert!(TopicFilter::new(value.to_owned(), Strictness::Lenient).is_ok());
+            let res = TopicName::new(value.to_owned(), Strictness::Strict);
+            assert!(matches!(res, Err(TopicNameError::ContainsNonCharacter { .. })));
+            let res = TopicFilter::new(value.to_owned(), Strictness::Strict);
+            assert!(matches!(res, Err(TopicFilterError::ContainsNonCharacter { .. })));
+        }
+        assert!(TopicName::new("home/\u{e9}t\u{e9}".to_owned(), Strictness::Strict).is_ok());
+        // An encoded UTF-16 surrogate is not valid UTF-8
+        let res = TopicName::from_bytes(b"home/\xed\xa0\x80", Strictness::Lenient);
+        assert!(matches!(res, Err(TopicNameError::InvalidUtf8 { .. })));
+        let res = TopicFilter::from_bytes(b"home/\xed\xa0\x80", Strictness::Lenient);
+        assert!(matches!(res, Err(TopicFilterError::InvalidUtf8 { .. })));
+        assert!(TopicFilter::from_bytes(b"home/+/#", Strictness::Strict).is_ok());
+    }
+
+    #[test]
+    fn test_topic_errors() {
+        let err = TopicName::try_from("home/+/light".to_owned()).unwrap_err();
+        assert!(matches!(err, TopicNameError::ContainsSingleLevelWildcard { position: 5 }));
+        assert_eq!(err.reason_code(), 0x90);
+        assert_eq!(err.to_string(), "topic name contains a single-level wildcard at byte 5");
+        let err = TopicName::from_bytes(b"ab\xff", Strictness::Lenient).unwrap_err();
+        assert_eq!(err.position(), Some(2));
+        for (value, position) in [("home/bed+", 8), ("home/+bed", 5), ("home/#/light", 5), ("home#", 4)] {
+            let err = TopicFilter::try_from(value.to_owned()).unwrap_err();
+            assert_eq!(err.position(), Some(position), "{value}");
+            assert_eq!(err.reason_code(), 0x8F);
+        }
+        for value in ["$share", "$share/group", "$share/group/", "$share//home", "$share/+/home"] {
+            let err = TopicFilter::try_from(value.to_owned()).unwrap_err();
+            assert!(matches!(err, TopicFilterError::InvalidSharedSubscription), "{value}");
+        }
+        let shared = TopicFilter::try_from("$share/group/home/#".to_owned()).unwrap();
+        let err = shared.ensure_unshared().unwrap_err();
+        assert_eq!(err.reason_code(), 0x9E);
+        assert!(TopicFilter::try_from("home/+/#".to_owned()).unwrap().ensure_unshared().is_ok());
+        // Errors fit into standard error chains
+        let boxed: Box<dyn std::error::Error> = Box::new(err);
+        assert_eq!(boxed.to_string(), "shared subscriptions are not supported");
+    }
+
+    #[test]
+    fn test_standard_traits() {
+        let mut filters = HashSet::new();
+        filters.insert(TopicFilter::try_from("home/+/light").unwrap());
+        filters.insert(TopicFilter::try_from("$share/group/home/+/light").unwrap());
+        filters.insert(TopicFilter::try_from("$share/other/home/+/light").unwrap());
+        filters.insert(TopicFilter::try_from("home/+/light".to_owned()).unwrap());
+        assert_eq!(filters.len(), 3);
+        let shared = TopicFilter::try_from("$share/group/home/#").unwrap();
+        assert_eq!(shared.to_string(), "$share/group/home/#");
+        assert_eq!(shared.filter_str(), "home/#");
+        assert_eq!(shared.shared_group(), Some("group"));
+        assert_eq!(format!("{shared:?}"), "TopicFilter(\"$share/group/home/#\")");
+
+        let topic = TopicName::try_from("home/bedroom/light").unwrap();
+        assert_eq!(topic, TopicName::try_from("home/bedroom/light".to_owned()).unwrap());
+        assert_ne!(topic, TopicName::try_from("home/bedroom").unwrap());
+        assert_eq!(topic.as_ref(), "home/bedroom/light");
+        assert_eq!(topic.levels(), 3);
+
+        assert_eq!(QoS::try_from(1), Ok(QoS::Level1));
+        assert_eq!(QoS::try_from(3), Err(InvalidQoS(3)));
+        assert_eq!(QoS::Level2.min(QoS::Level1), QoS::Level1);
+        assert_eq!(u8::from(QoS::Level2), 2);
+
         let mut t = TopicTree::default();
-        let s1 = TopicFilter::try_from("home/+/+".to_owned()).unwrap();
-        t.add_subscription(s1, 1, QoS::Level0);
-        let s2 = TopicFilter::try_from("home/#".to_owned()).unwrap();
-        t.add_subscription(s2, 2, QoS::Level0);
-        let s3 = TopicFilter::try_from("home/+/#".to_owned()).unwrap();
-        t.add_subscription(s3, 3, QoS::Level0);
-        let topic = TopicName::try_from("home/bedroom/light".to_owned()).unwrap();
-        let num_ops = 100000;
-        let t_start = Instant::now();
-        for _ in 0..num_ops {
-            let _ = t.get_subscriptions(&topic);
+        t.add_subscription(TopicFilter::try_from("home/#").unwrap(), 1, QoS::Level1).unwrap();
+        let expected = vec![Subscriber { client_id: 1, qos: QoS::Level1 }];
+        assert_eq!(t.get_subscriptions(&topic), expected);
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let cases = [
+            ("home/+/light", "home/bedroom/light", true),
+            ("home/+/light", "home/bedroom/lamp", false),
+            ("home/+/light", "home/light", false),
+            ("home/+", "home/", true),
+            ("home/#", "home", true),
+            ("home/#", "home/bedroom/light", true),
+            ("home/#", "homes", false),
+            ("#", "home/bedroom", true),
+            ("#", "$SYS/uptime", false),
+            ("+/uptime", "$SYS/uptime", false),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/+", "$SYS/uptime", true),
+            ("$share/group/home/#", "home/bedroom", true),
+        ];
+        let mut t = TopicTree::default();
+        for (idx, (filter, topic, expected)) in cases.into_iter().enumerate() {
+            let filter = TopicFilter::try_from(filter).unwrap();
+            let topic = TopicName::try_from(topic).unwrap();
+            assert_eq!(filter.matches(&topic), expected, "{filter} {topic}");
+            t.add_subscription(filter, idx as ClientId, QoS::Level0).unwrap();
+            let matched = t.get_subscriptions(&topic).iter().any(|x| x.client_id == idx as ClientId);
+            assert_eq!(matched, expected, "{idx} {topic}");
+        }
+    }
+
+    /// All the strings that can be built from up to `max_levels` of the given levels, `#` is only
+    /// used as the last level
+    fn all_topics(alphabet: &[&str], max_levels: usize) -> Vec<String> {
+        let mut results: Vec<String> = Vec::new();
+        let mut prefixes = vec![String::new()];
+        for depth in 0..max_levels {
+            let mut next_prefixes = Vec::new();
+            for prefix in prefixes.iter() {
+                for level in alphabet {
+                    let topic = match depth {
+                        0 => level.to_string(),
+                        _ => format!("{prefix}/{level}"),
+                    };
+                    if *level != "#" {
+                        next_prefixes.push(topic.clone());
+                    }
+                    results.push(topic);
+                }
+            }
+            prefixes = next_prefixes;
+        }
+        results
+    }
+
+    #[test]
+    fn test_filter_algebra_exhaustive() {
+        let filters: Vec<TopicFilter> = all_topics(&["a", "$a", "+", "#"], 3)
+            .into_iter()
+            .map(|x| TopicFilter::try_from(x).unwrap())
+            .collect();
+        // Topics need a level the filters don't use and one more level than the filters
+        let topics: Vec<TopicName> = all_topics(&["a", "b", "$a", "$b"], 4)
+            .into_iter()
+            .map(|x| TopicName::try_from(x).unwrap())
+            .collect();
+        let matches: Vec<Vec<bool>> = filters
+            .iter()
+            .map(|filter| topics.iter().map(|topic| filter.matches(topic)).collect())
+            .collect();
+        for (i, a) in filters.iter().enumerate() {
+            for (j, b) in filters.iter().enumerate() {
+                let subset = (0..topics.len()).all(|k| !matches[i][k] || matches[j][k]);
+                assert_eq!(a.is_subset_of(b), subset, "{a} subset of {b}");
+                let overlap = (0..topics.len()).any(|k| matches[i][k] && matches[j][k]);
+                assert_eq!(a.overlaps(b), overlap, "{a} overlaps {b}");
+                if let Some(intersection) = a.intersection(b) {
+                    for (k, topic) in topics.iter().enumerate() {
+                        let expected = matches[i][k] && matches[j][k];
+                        assert_eq!(intersection.matches(topic), expected, "{a} & {b} = {intersection}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_filter_algebra() {
+        assert!(filter("home/+/light").is_subset_of(&filter("home/#")));
+        assert!(filter("home").is_subset_of(&filter("home/#")));
+        assert!(!filter("home/#").is_subset_of(&filter("home/+/#")));
+        assert!(!filter("$SYS/uptime").is_subset_of(&filter("#")));
+        assert!(filter("$share/group/home/+").is_subset_of(&filter("home/#")));
+        assert!(!filter("+/light").overlaps(&filter("$SYS/+")));
+        assert_eq!(filter("home/#").intersection(&filter("+/+/light")), Some(filter("home/+/light")));
+        assert_eq!(filter("home/#").intersection(&filter("+")), Some(filter("home")));
+        assert_eq!(filter("home/+").intersection(&filter("home/a/b")), None);
+    }
+
+    fn filter_strategy() -> impl Strategy<Value = TopicFilter> {
+        let level = prop::sample::select(vec!["a", "b", "", "$x", "+"]);
+        let levels = prop::collection::vec(level, 1..5);
+        (levels, any::<bool>(), prop::option::of(prop::sample::select(vec!["g1", "g2"])))
+            .prop_map(|(mut levels, multi_level, shared_group)| {
+                if multi_level {
+                    *levels.last_mut().unwrap() = "#";
+                }
+                // A single empty level would be an empty filter
+                if levels == [""] {
+                    levels[0] = "a";
+                }
+                let filter = levels.join("/");
+                match shared_group {
+                    None => TopicFilter::try_from(filter).unwrap(),
+                    Some(group) => TopicFilter::try_from(format!("$share/{group}/{filter}")).unwrap(),
+                }
+            })
+    }
+
+    fn topic_strategy() -> impl Strategy<Value = TopicName> {
+        let level = prop::sample::select(vec!["a", "b", "", "$x"]);
+        prop::collection::vec(level, 1..6)
+            .prop_filter("topic can not be empty", |levels| levels != &[""])
+            .prop_map(|levels| TopicName::try_from(levels.join("/")).unwrap())
+    }
+
+    proptest! {
+        #[test]
+        fn prop_filter_matches_agrees_with_tree(
+            filters in prop::collection::vec(filter_strategy(), 1..30),
+            topics in prop::collection::vec(topic_strategy(), 1..10),
+        ) {
+            // Every shared group gets a single member, so the group always picks that client
+            let filters: Vec<TopicFilter> = filters
+                .into_iter()
+                .enumerate()
+                .map(|(client_id, filter)| match filter.shared_group() {
+                    None => filter,
+                    Some(group) => {
+                        let filter = format!("$share/{group}{client_id}/{}", filter.filter_str());
+                        TopicFilter::try_from(filter).unwrap()
+                    }
+                })
+                .collect();
+            let mut t = TopicTree::default();
+            for (client_id, filter) in filters.iter().enumerate() {
+                t.add_subscription(filter.clone(), client_id as ClientId, QoS::Level0).unwrap();
+            }
+            for topic in topics.iter() {
+                let mut matched: Vec<ClientId> =
+                    t.get_subscriptions(topic).iter().map(|x| x.client_id).collect();
+                matched.sort();
+                let expected: Vec<ClientId> = filters
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, filter)| filter.matches(topic))
+                    .map(|(client_id, _)| client_id as ClientId)
+                    .collect();
+                prop_assert_eq!(matched, expected, "topic {}", topic);
+            }
+        }
+    }
+
+    /// The matching rules of the spec applied level by level, without any of the tricks of the tree
+    fn reference_matches(filter: &str, topic: &str) -> bool {
+        let filter: Vec<&str> = filter.split('/').collect();
+        let topic: Vec<&str> = topic.split('/').collect();
+        if topic[0].starts_with('$') && (filter[0] == "+" || filter[0] == "#") {
+            return false;
+        }
+        for (i, level) in filter.iter().enumerate() {
+            match *level {
+                // Also matches the parent level, which is the case when i == topic.len()
+                "#" => return true,
+                "+" if i < topic.len() => {}
+                _ if topic.get(i) == Some(level) => {}
+                _ => return false,
+            }
+        }
+        filter.len() == topic.len()
+    }
+
+    #[derive(Clone, Debug)]
+    enum Operation {
+        Add(TopicFilter, ClientId, QoS),
+        Remove(TopicFilter, ClientId),
+        /// Removes a subscription that is in the model, if there is one
+        RemoveExisting(prop::sample::Index),
+        RemoveClient(ClientId),
+        Lookup(TopicName),
+    }
+
+    fn operation_strategy() -> impl Strategy<Value = Operation> {
+        let qos = (0..3u8).prop_map(|x| QoS::try_from(x).unwrap());
+        prop_oneof![
+            4 => (filter_strategy(), 0..4u64, qos).prop_map(|(f, c, q)| Operation::Add(f, c, q)),
+            1 => (filter_strategy(), 0..4u64).prop_map(|(f, c)| Operation::Remove(f, c)),
+            2 => any::<prop::sample::Index>().prop_map(Operation::RemoveExisting),
+            1 => (0..4u64).prop_map(Operation::RemoveClient),
+            4 => topic_strategy().prop_map(Operation::Lookup),
+        ]
+    }
+
+    /// Checks a lookup against a linear scan of the model. Subscriptions without a share group
+    /// must all be in the result, and every matching share group must add one of its members.
+    fn check_lookup(
+        model: &[(TopicFilter, ClientId, QoS)],
+        topic: &TopicName,
+        mut result: Vec<Subscriber>,
+    ) -> Result<(), String> {
+        let mut groups: Vec<((&str, &str), Vec<Subscriber>)> = Vec::new();
+        for (filter, client_id, qos) in model.iter() {
+            if !reference_matches(filter.filter_str(), topic.as_str()) {
+                continue;
+            }
+            let subscriber = Subscriber { client_id: *client_id, qos: *qos };
+            let Some(group) = filter.shared_group() else {
+                let Some(idx) = result.iter().position(|x| *x == subscriber) else {
+                    return Err(format!("{subscriber:?} of {filter} is missing for {topic}"));
+                };
+                result.swap_remove(idx);
+                continue;
+            };
+            let key = (group, filter.filter_str());
+            match groups.iter_mut().find(|(x, _)| *x == key) {
+                Some((_, members)) => members.push(subscriber),
+                None => groups.push((key, vec![subscriber])),
+            }
+        }
+        let members: Vec<Vec<Subscriber>> = groups.into_iter().map(|(_, x)| x).collect();
+        if !assign_groups(&mut result, &members) {
+            return Err(format!("{result:?} is not one member of each of {members:?} for {topic}"));
+        }
+        Ok(())
+    }
+
+    /// Whether the subscribers can be split so every group gets exactly one of its members
+    fn assign_groups(result: &mut Vec<Subscriber>, groups: &[Vec<Subscriber>]) -> bool {
+        let Some((members, groups)) = groups.split_first() else {
+            return result.is_empty();
+        };
+        for i in 0..result.len() {
+            if !members.contains(&result[i]) {
+                continue;
+            }
+            let picked = result.swap_remove(i);
+            if assign_groups(result, groups) {
+                return true;
+            }
+            result.push(picked);
+            let last = result.len() - 1;
+            result.swap(i, last);
+        }
+        false
+    }
+
+    proptest! {
+        #[test]
+        fn prop_trees_agree_with_linear_scan(
+            operations in prop::collection::vec(operation_strategy(), 1..60),
+        ) {
+            let mut model: Vec<(TopicFilter, ClientId, QoS)> = Vec::new();
+            let mut t = TopicTree::default();
+            let mqtt_tree = MqttTopicTree::default();
+            for operation in operations {
+                match operation {
+                    Operation::Add(filter, client_id, qos) => {
+                        match model.iter_mut().find(|(f, c, _)| *f == filter && *c == client_id) {
+                            Some(subscription) => subscription.2 = qos,
+                            None => model.push((filter.clone(), client_id, qos)),
+                        }
+                        t.add_subscription(filter.clone(), client_id, qos).unwrap();
+                        mqtt_tree.add_subscription(filter, client_id, qos).unwrap();
+                    }
+                    Operation::Remove(filter, client_id) => {
+                        let existed = model.iter().any(|(f, c, _)| *f == filter && *c == client_id);
+                        model.retain(|(f, c, _)| !(*f == filter && *c == client_id));
+                        prop_assert_eq!(t.remove_subscription(filter.clone(), client_id), existed);
+                        mqtt_tree.remove_subscription(filter, client_id).unwrap();
+                    }
+                    Operation::RemoveExisting(index) => {
+                        if model.is_empty() {
+                            continue;
+                        }
+                        let (filter, client_id, _) = model.remove(index.index(model.len()));
+                        prop_assert!(t.remove_subscription(filter.clone(), client_id));
+                        mqtt_tree.remove_subscription(filter, client_id).unwrap();
+                    }
+                    Operation::RemoveClient(client_id) => {
+                        let count = model.iter().filter(|(_, c, _)| *c == client_id).count();
+                        model.retain(|(_, c, _)| *c != client_id);
+                        prop_assert_eq!(t.remove_client(client_id), count);
+                        mqtt_tree.remove_client(client_id).unwrap();
+                    }
+                    Operation::Lookup(topic) => {
+                        let result = check_lookup(&model, &topic, t.get_subscriptions(&topic));
+                        prop_assert!(result.is_ok(), "TopicTree: {}", result.unwrap_err());
+                        let result = check_lookup(&model, &topic, mqtt_tree.get_subscriptions(&topic));
+                        prop_assert!(result.is_ok(), "MqttTopicTree: {}", result.unwrap_err());
+                    }
+                }
+            }
+            // Nothing is left behind by the removals
+            prop_assert_eq!(t.iter().count(), model.len());
+            prop_assert_eq!(mqtt_tree.iter().count(), model.len());
+            prop_assert_eq!(t.stats().nodes == 0, model.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_topic_index() {
+        let topics = all_topics(&["a", "b", "$a"], 3);
+        let filters = all_topics(&["a", "$a", "+", "#"], 3);
+        let mut index = TopicIndex::default();
+        for topic in topics.iter() {
+            assert!(index.insert(TopicName::try_from(topic.as_str()).unwrap()));
+        }
+        assert!(!index.insert(TopicName::try_from("a/b").unwrap()));
+        assert_eq!(index.len(), topics.len());
+        let check = |index: &TopicIndex, topics: &[String]| {
+            for filter in filters.iter() {
+                let filter = TopicFilter::try_from(filter.as_str()).unwrap();
+                let mut matched: Vec<String> =
+                    index.matching(&filter).iter().map(|x| x.to_string()).collect();
+                matched.sort();
+                let mut expected: Vec<String> = topics
+                    .iter()
+                    .filter(|x| filter.matches(&TopicName::try_from(x.as_str()).unwrap()))
+                    .cloned()
+                    .collect();
+                expected.sort();
+                assert_eq!(matched, expected, "{filter}");
+            }
+        };
+        check(&index, &topics);
+        // Remove every other topic, including ones that still have topics below them
+        let removed: Vec<String> = topics.iter().step_by(2).cloned().collect();
+        let kept: Vec<String> = topics.iter().skip(1).step_by(2).cloned().collect();
+        for topic in removed.iter() {
+            assert!(index.remove(&TopicName::try_from(topic.as_str()).unwrap()));
+        }
+        assert!(!index.remove(&TopicName::try_from(removed[0].as_str()).unwrap()));
+        assert_eq!(index.len(), kept.len());
+        assert!(index.contains(&TopicName::try_from(kept[0].as_str()).unwrap()));
+        check(&index, &kept);
+
+        let index = MqttTopicIndex::default();
+        index.insert(TopicName::try_from("home/bedroom/light").unwrap());
+        index.insert(TopicName::try_from("home/kitchen/light").unwrap());
+        index.insert(TopicName::try_from("$SYS/uptime").unwrap());
+        index.remove(TopicName::try_from("home/kitchen/light").unwrap());
+        let matched = index.matching(&TopicFilter::try_from("+/+/light").unwrap());
+        assert_eq!(matched, vec![TopicName::try_from("home/bedroom/light").unwrap()]);
+        assert!(index.matching(&TopicFilter::try_from("#").unwrap()).len() == 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let filter = TopicFilter::try_from("$share/group/home/+").unwrap();
+        let json = serde_json::to_string(&filter).unwrap();
+        assert_eq!(json, "\"$share/group/home/+\"");
+        assert_eq!(serde_json::from_str::<TopicFilter>(&json).unwrap(), filter);
+        assert!(serde_json::from_str::<TopicFilter>("\"home/#/light\"").is_err());
+        assert!(serde_json::from_str::<TopicName>("\"home/+\"").is_err());
+        assert!(serde_json::from_str::<QoS>("3").is_err());
+        let subscriber = Subscriber { client_id: 7, qos: QoS::Level2 };
+        let json = serde_json::to_string(&subscriber).unwrap();
+        assert_eq!(json, "{\"client_id\":7,\"qos\":2}");
+
+        let mut t = TopicTree::default();
+        let subscriptions = [
+            ("home/+/light", 1, QoS::Level0),
+            ("home/#", 2, QoS::Level1),
+            ("#", 3, QoS::Level2),
+            ("home/bedroom/light", 3, QoS::Level0),
+            ("$share/group/home/+/light", 4, QoS::Level1),
+            ("$share/group/home/+/light", 5, QoS::Level1),
+        ];
+        for (filter, client_id, qos) in subscriptions {
+            t.add_subscription(TopicFilter::try_from(filter).unwrap(), client_id, qos).unwrap();
+        }
+        let json = serde_json::to_string(&t).unwrap();
+        let restored: TopicTree = serde_json::from_str(&json).unwrap();
+        let mut original: Vec<_> = t.subscriptions().collect();
+        let mut restored: Vec<_> = restored.subscriptions().collect();
+        original.sort_by_key(|(filter, subscriber)| (filter.to_string(), subscriber.client_id));
+        restored.sort_by_key(|(filter, subscriber)| (filter.to_string(), subscriber.client_id));
+        assert_eq!(original, restored);
+        assert_eq!(original.len(), subscriptions.len());
+    }
+
+    /// The sorted (client id, qos) pairs the tree returns for each topic
+    fn sorted_subscriptions(t: &TopicTree, topics: &[TopicName]) -> Vec<Vec<(ClientId, QoS)>> {
+        topics
+            .iter()
+            .map(|topic| sorted(t.get_subscriptions(topic)))
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut t = TopicTree::default();
+        let subscriptions = [
+            ("home/+/light", 1, QoS::Level0),
+            ("home/#", 2, QoS::Level1),
+            ("#", 3, QoS::Level2),
+            ("home/bedroom/light", 3, QoS::Level0),
+            ("home/kitchen/light", 3, QoS::Level1),
+            ("$share/group/home/+/light", 4, QoS::Level1),
+            ("$share/group/home/+/light", 4, QoS::Level1),
+            ("$SYS/#", 5, QoS::Level0),
+        ];
+        for (filter, client_id, qos) in subscriptions {
+            t.add_subscription(TopicFilter::try_from(filter).unwrap(), client_id, qos).unwrap();
+        }
+        let topics: Vec<TopicName> = ["home/bedroom/light", "home/kitchen", "$SYS/uptime", "home"]
+            .into_iter()
+            .map(|x| TopicName::try_from(x).unwrap())
+            .collect();
+        let mut buf = Vec::new();
+        t.write_snapshot(&mut buf).unwrap();
+        let mut restored = TopicTree::read_snapshot(buf.as_slice()).unwrap();
+        assert_eq!(sorted_subscriptions(&t, &topics), sorted_subscriptions(&restored, &topics));
+        // The counters are rebuilt, so removing everything leaves an empty tree
+        for (filter, client_id, _) in subscriptions {
+            restored.remove_subscription(TopicFilter::try_from(filter).unwrap(), client_id);
+        }
+        assert_eq!((restored.subscribers, restored.nodes), (0, 0));
+        assert!(restored.client_subscriptions.is_empty());
+        // The level name "light" is only stored once
+        let light_count = buf.windows(5).filter(|x| x == b"light").count();
+        assert_eq!(light_count, 1);
+
+        let mut corrupt = buf.clone();
+        corrupt[12] ^= 1;
+        let res = TopicTree::read_snapshot(corrupt.as_slice());
+        assert!(matches!(res, Err(SnapshotError::ChecksumMismatch)));
+        let res = TopicTree::read_snapshot(&b"not a snapshot"[..]);
+        assert!(matches!(res, Err(SnapshotError::InvalidMagic)));
+        let mut newer = buf.clone();
+        newer[4] = 2;
+        let res = TopicTree::read_snapshot(newer.as_slice());
+        assert!(matches!(res, Err(SnapshotError::UnsupportedVersion(2))));
+
+        // Crafted snapshots with a valid checksum, the nodes are nested under the first string
+        let crafted = |strings: &[&str], depth: usize, group: bool| {
+            let mut data = b"MTTS\x01\x00".to_vec();
+            data.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            for string in strings {
+                data.extend_from_slice(&(string.len() as u32).to_le_bytes());
+                data.extend_from_slice(string.as_bytes());
+            }
+            let empty = [0u8; 9];
+            for _ in 0..depth {
+                data.extend_from_slice(&empty);
+                data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+            }
+            // A subscription of client 1, either directly or in the group of the last string
+            data.extend_from_slice(&[!group as u8, 0, 0, 0]);
+            if group {
+                data.extend_from_slice(&[1, 0, 0, 0]);
+                data.extend_from_slice(&(strings.len() as u32 - 1).to_le_bytes());
+                data.extend_from_slice(&[1, 0, 0, 0]);
+            }
+            data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0]);
+            if !group {
+                data.extend_from_slice(&[0, 0, 0, 0]);
+            }
+            data.extend_from_slice(&[0, 0, 0, 0, 0]);
+            let checksum = crate::snapshot::crc32(&data);
+            data.extend_from_slice(&checksum.to_le_bytes());
+            TopicTree::read_snapshot(data.as_slice())
+        };
+        assert_eq!(crafted(&["a", "g"], 2, true).unwrap().iter().count(), 1);
+        for (strings, depth, group) in [
+            (&["a/b"][..], 1, false),
+            (&["+"][..], 1, false),
+            (&["a#"][..], 1, false),
+            (&["a\0"][..], 1, false),
+            (&["$share"][..], 1, false),
+            // A subscription on the root would have an empty filter
+            (&["a"][..], 0, false),
+            (&["a", "g/h"][..], 1, true),
+            (&["a", ""][..], 1, true),
+            (&["a"][..], 2000, false),
+        ] {
+            let res = crafted(strings, depth, group);
+            assert!(matches!(res, Err(SnapshotError::Corrupt)), "{strings:?}");
+        }
+
+        // The deepest filter a snapshot holds round trips, a deeper one is refused when writing
+        let mut deep = TopicTree::default();
+        deep.add_subscription(filter(&format!("{}+", "a/".repeat(255))), 1, QoS::Level1).unwrap();
+        let mut deep_buf = Vec::new();
+        deep.write_snapshot(&mut deep_buf).unwrap();
+        let restored = TopicTree::read_snapshot(deep_buf.as_slice()).unwrap();
+        assert_eq!(restored.iter().collect::<Vec<_>>(), deep.iter().collect::<Vec<_>>());
+        deep.add_subscription(filter(&"/".repeat(256)), 2, QoS::Level0).unwrap();
+        let err = deep.write_snapshot(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // A durable tree keeps its old snapshot and log, so nothing is lost on recovery
+        let dir = tempfile::tempdir().unwrap();
+        let durable = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        durable.add_subscription(filter("home/#"), 1, QoS::Level0).unwrap();
+        durable.checkpoint().unwrap();
+        durable.add_subscription(filter(&"/".repeat(256)), 2, QoS::Level0).unwrap();
+        assert!(durable.checkpoint().is_err());
+        drop(durable);
+        let durable = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        assert_eq!(durable.iter().count(), 2);
+
+        let mqtt_tree = MqttTopicTree::from_snapshot(buf.as_slice()).unwrap();
+        assert_eq!(mqtt_tree.get_subscriptions(&topics[2]).len(), 1);
+        let mut mqtt_buf = Vec::new();
+        mqtt_tree.write_snapshot(&mut mqtt_buf).unwrap();
+        let restored = TopicTree::read_snapshot(mqtt_buf.as_slice()).unwrap();
+        assert_eq!(sorted_subscriptions(&t, &topics), sorted_subscriptions(&restored, &topics));
+    }
+
+    /// The sorted (client id, qos) pairs a MqttTopicTree returns for each topic
+    fn sorted_mqtt_subscriptions(t: &MqttTopicTree, topics: &[&str]) -> Vec<Vec<(ClientId, QoS)>> {
+        topics
+            .iter()
+            .map(|topic| sorted(t.get_subscriptions(&TopicName::try_from(*topic).unwrap())))
+            .collect()
+    }
+
+    #[test]
+    fn test_write_ahead_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join(crate::wal::LOG_FILE);
+        let topics = ["home/bedroom/light", "home/kitchen", "office/desk"];
+
+        let t = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        t.add_subscription(filter("home/+/light"), 1, QoS::Level1).unwrap();
+        t.add_subscription(filter("home/#"), 2, QoS::Level0).unwrap();
+        t.add_subscription(filter("$share/group/office/+"), 3, QoS::Level2).unwrap();
+        t.remove_subscription(filter("home/+/light"), 1).unwrap();
+        let mut batch = t.batch();
+        batch.add_subscription(filter("office/#"), 4, QoS::Level1).unwrap();
+        batch.add_subscription(filter("home/kitchen"), 4, QoS::Level1).unwrap();
+        batch.commit().unwrap();
+        t.remove_client(2).unwrap();
+        let expected = sorted_mqtt_subscriptions(&t, &topics);
+        drop(t);
+
+        let t = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        assert_eq!(sorted_mqtt_subscriptions(&t, &topics), expected);
+        let log_len = std::fs::metadata(&log_path).unwrap().len();
+        // A batch that misses its end record is dropped completely
+        let mut batch = t.batch();
+        batch.add_subscription(filter("office/desk"), 5, QoS::Level0).unwrap();
+        batch.add_subscription(filter("home/kitchen"), 5, QoS::Level0).unwrap();
+        batch.commit().unwrap();
+        drop(t);
+        let file = std::fs::OpenOptions::new().write(true).open(&log_path).unwrap();
+        file.set_len(std::fs::metadata(&log_path).unwrap().len() - 3).unwrap();
+        drop(file);
+        let t = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        assert_eq!(sorted_mqtt_subscriptions(&t, &topics), expected);
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), log_len);
+        drop(t);
+
+        // A torn final record is truncated
+        let mut file = std::fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        std::io::Write::write_all(&mut file, &[20, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+        let t = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        assert_eq!(sorted_mqtt_subscriptions(&t, &topics), expected);
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), log_len);
+        drop(t);
+
+        // A bad record that is followed by others is corruption, and the log is left as it is
+        let mut log = std::fs::read(&log_path).unwrap();
+        log[20] ^= 0xff;
+        std::fs::write(&log_path, &log).unwrap();
+        assert!(matches!(
+            MqttTopicTree::recover(dir.path(), TopicLimits::default()),
+            Err(RecoveryError::CorruptLog { offset: 6 })
+        ));
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), log_len);
+        log[20] ^= 0xff;
+        // So is a damaged length that points past the end of the log
+        log[9] ^= 0x80;
+        std::fs::write(&log_path, &log).unwrap();
+        assert!(matches!(
+            MqttTopicTree::recover(dir.path(), TopicLimits::default()),
+            Err(RecoveryError::CorruptLog { offset: 6 })
+        ));
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), log_len);
+        log[9] ^= 0x80;
+        // A complete final record with a bad checksum is a torn write
+        let last = log.len() - 1;
+        log[last] ^= 0xff;
+        std::fs::write(&log_path, &log).unwrap();
+        let t = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        assert!(std::fs::metadata(&log_path).unwrap().len() < log_len);
+        drop(t);
+        log[last] ^= 0xff;
+        std::fs::write(&log_path, &log).unwrap();
+        let t = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        assert_eq!(sorted_mqtt_subscriptions(&t, &topics), expected);
+
+        // A checkpoint moves the state into the snapshot and empties the log
+        t.checkpoint().unwrap();
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), 6);
+        t.add_subscription(filter("office/desk"), 6, QoS::Level0).unwrap();
+        let expected = sorted_mqtt_subscriptions(&t, &topics);
+        drop(t);
+        let t = MqttTopicTree::recover(dir.path(), TopicLimits::default()).unwrap();
+        assert_eq!(sorted_mqtt_subscriptions(&t, &topics), expected);
+
+        assert!(MqttTopicTree::default().checkpoint().is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = TopicLimits {
+            max_levels: 3,
+            max_bytes: 20,
+            max_level_length: 8,
+            max_subscriptions_per_client: 2,
+            max_total_nodes: 4,
+        };
+        let mut t = TopicTree::with_limits(limits.clone());
+        let res = t.add_subscription(filter("a/b/c/d"), 1, QoS::Level0);
+        assert!(matches!(res, Err(SubscriptionError::TooManyLevels { levels: 4, max: 3 })));
+        let res = t.add_subscription(filter("a/b/abcdefghijklmnop"), 1, QoS::Level0);
+        assert!(matches!(res, Err(SubscriptionError::LevelTooLong { level: 2, max: 8 })));
+        let res = t.add_subscription(filter("$share/group/a/bcdefgh"), 1, QoS::Level0);
+        assert!(matches!(res, Err(SubscriptionError::TooLong { bytes: 22, max: 20 })));
+        // The shared prefix does not count as levels
+        t.add_subscription(filter("$share/g/a/b/c"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("a/+"), 1, QoS::Level0).unwrap();
+        let res = t.add_subscription(filter("a/#"), 1, QoS::Level0);
+        assert_eq!(res.unwrap_err().reason_code(), 0x97);
+        // Re-subscribing only replaces the QoS and does not count against the quota
+        t.add_subscription(filter("a/+"), 1, QoS::Level1).unwrap();
+        // a, a/b, a/b/c and a/+ are all the nodes we are allowed
+        let res = t.add_subscription(filter("a/#"), 2, QoS::Level0);
+        assert!(matches!(res, Err(SubscriptionError::TooManyNodes { max: 4 })));
+        t.add_subscription(filter("a/b"), 2, QoS::Level0).unwrap();
+        // Removing subscriptions frees up quota and prunes nodes
+        assert!(t.remove_subscription(filter("$share/g/a/b/c"), 1));
+        assert!(!t.remove_subscription(filter("$share/g/a/b/c"), 1));
+        t.add_subscription(filter("a/#"), 1, QoS::Level0).unwrap();
+
+        let t = MqttTopicTree::with_limits(limits);
+        t.add_subscription(filter("a/+"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("a/#"), 1, QoS::Level0).unwrap();
+        let res = t.add_subscription(filter("b"), 1, QoS::Level0);
+        let expected = SubscriptionError::TooManySubscriptions { max: 2 };
+        assert!(matches!(res, Err(WriteError::Rejected(e)) if e.to_string() == expected.to_string()));
+        // Quota is counted across a batch
+        let mut batch = t.batch();
+        batch.remove_client(1);
+        batch.add_subscription(filter("a/+"), 2, QoS::Level0).unwrap();
+        batch.add_subscription(filter("a/#"), 2, QoS::Level0).unwrap();
+        let res = batch.add_subscription(filter("a"), 2, QoS::Level0);
+        assert!(matches!(res, Err(SubscriptionError::TooManySubscriptions { max: 2 })));
+        batch.commit().unwrap();
+        let subscribers = t.get_subscriptions(&TopicName::try_from("a/b").unwrap());
+        assert_eq!(subscribers.len(), 2);
+        assert!(subscribers.iter().all(|x| x.client_id == 2));
+    }
+
+    #[test]
+    fn test_subscription_events() {
+        use SubscriptionEvent::*;
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut t = TopicTree::default();
+        let sender = std::sync::Mutex::new(tx.clone());
+        t.set_listener(move |event| sender.lock().unwrap().send(event.clone()).unwrap());
+        t.add_subscription(filter("a/+"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("a/+"), 2, QoS::Level1).unwrap();
+        t.add_subscription(filter("$share/g/a/#"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("$share/g/a/#"), 2, QoS::Level0).unwrap();
+        // Rejected and missing subscriptions emit nothing
+        assert!(!t.remove_subscription(filter("a/b"), 1));
+        assert!(t.remove_subscription(filter("a/+"), 1));
+        assert_eq!(t.remove_client(2), 2);
+        let events: Vec<SubscriptionEvent> = rx.try_iter().collect();
+        let subscribed = |x: &str, client_id: ClientId, qos: QoS| ClientSubscribed {
+            topic_filter: filter(x),
+            client_id,
+            qos,
+        };
+        let unsubscribed = |x: &str, client_id: ClientId| ClientUnsubscribed {
+            topic_filter: filter(x),
+            client_id,
+        };
+        assert_eq!(events[..7], [
+            FilterActivated(filter("a/+")),
+            subscribed("a/+", 1, QoS::Level0),
+            subscribed("a/+", 2, QoS::Level1),
+            FilterActivated(filter("$share/g/a/#")),
+            subscribed("$share/g/a/#", 1, QoS::Level0),
+            subscribed("$share/g/a/#", 2, QoS::Level0),
+            unsubscribed("a/+", 1),
+        ]);
+        // The order in which a client is removed from its filters is not defined
+        let mut rest: Vec<String> = events[7..].iter().map(|x| format!("{x:?}")).collect();
+        rest.sort();
+        let mut expected: Vec<String> = [
+            unsubscribed("a/+", 2),
+            FilterDeactivated(filter("a/+")),
+            unsubscribed("$share/g/a/#", 2),
+        ]
+        .iter()
+        .map(|x| format!("{x:?}"))
+        .collect();
+        expected.sort();
+        assert_eq!(rest, expected);
+
+        // Events are emitted once, not once for each copy of the tree
+        let sender = std::sync::Mutex::new(tx);
+        let t = MqttTopicTree::with_listener(TopicLimits::default(), move |event| {
+            sender.lock().unwrap().send(event.clone()).unwrap()
+        });
+        t.add_subscription(filter("a/#"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("b"), 1, QoS::Level0).unwrap();
+        t.remove_subscription(filter("b"), 1).unwrap();
+        let mut batch = t.batch();
+        batch.remove_client(1);
+        batch.add_subscription(filter("a/#"), 2, QoS::Level0).unwrap();
+        batch.commit().unwrap();
+        let events: Vec<SubscriptionEvent> = rx.try_iter().collect();
+        assert_eq!(events, [
+            FilterActivated(filter("a/#")),
+            subscribed("a/#", 1, QoS::Level0),
+            FilterActivated(filter("b")),
+            subscribed("b", 1, QoS::Level0),
+            unsubscribed("b", 1),
+            FilterDeactivated(filter("b")),
+            unsubscribed("a/#", 1),
+            FilterDeactivated(filter("a/#")),
+            FilterActivated(filter("a/#")),
+            subscribed("a/#", 2, QoS::Level0),
+        ]);
+    }
+
+    #[test]
+    fn test_cluster_routes() {
+        let topic = TopicName::try_from("home/bedroom/light").unwrap();
+        let mut routes = ClusterRoutes::default();
+        assert!(routes.add_route(filter("home/#"), 1));
+        assert!(!routes.add_route(filter("home/#"), 1));
+        assert!(routes.add_route(filter("home/+/light"), 1));
+        assert!(routes.add_route(filter("home/bedroom/light"), 2));
+        assert!(routes.add_route(filter("$share/g/home/+/light"), 2));
+        assert!(routes.add_route(filter("$share/g/home/+/light"), 3));
+        routes.apply(RouteUpdate::Add(filter("$SYS/#"), 4));
+        assert_eq!(routes.len(), 6);
+        assert_eq!(routes.ref_count(&filter("home/#"), 1), 2);
+
+        // Every node is listed once, and the shared group is routed to exactly one node
+        let mut picked = HashSet::new();
+        for _ in 0..100 {
+            let result = routes.route(&topic);
+            let node_ids: Vec<u64> = result.iter().map(|x| x.node_id).collect();
+            assert!(node_ids == [1, 2] || node_ids == [1, 2, 3], "{node_ids:?}");
+            let shared: Vec<_> = result
+                .iter()
+                .flat_map(|x| x.shared_filters.iter().map(move |y| (x.node_id, y.clone())))
+                .collect();
+            assert_eq!(shared.len(), 1);
+            assert_eq!(shared[0].1, filter("$share/g/home/+/light"));
+            picked.insert(shared[0].0);
         }
-        let t_end = Instant::now();
-        let t_delta = (t_end - t_start).as_nanos() / num_ops as u128;
-        println!("Topictree Lookup took {t_delta} ns per iteration");
+        assert_eq!(picked, HashSet::from([2, 3]));
+        assert!(routes.route(&TopicName::try_from("$SYS/home").unwrap())[0].node_id == 4);
+
+        // The node only delivers to the shared group it was picked for
+        let mut t = TopicTree::default();
+        t.add_subscription(filter("home/#"), 10, QoS::Level0).unwrap();
+        t.add_subscription(filter("$share/g/home/+/light"), 11, QoS::Level1).unwrap();
+        t.add_subscription(filter("$share/g/home/#"), 12, QoS::Level1).unwrap();
+        let ids = |x: Vec<Subscriber>| {
+            let mut ids: Vec<ClientId> = x.iter().map(|x| x.client_id).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(t.get_routed_subscriptions(&topic, &[])), [10]);
+        let shared_filters = [filter("$share/g/home/+/light")];
+        assert_eq!(ids(t.get_routed_subscriptions(&topic, &shared_filters)), [10, 11]);
+
+        // Routes are only removed with their last reference
+        assert!(!routes.remove_route(&filter("home/#"), 1));
+        assert!(routes.remove_route(&filter("home/#"), 1));
+        assert!(!routes.remove_route(&filter("home/#"), 1));
+        routes.apply(RouteUpdate::RemoveNode(2));
+        assert_eq!(routes.remove_node(3), 1);
+        let result = routes.route(&topic);
+        assert_eq!(result.len(), 1);
+        assert!(result[0].node_id == 1 && result[0].shared_filters.is_empty());
+
+        // Events of the local tree are turned into updates for the peers
+        let (tx, rx) = std::sync::mpsc::channel();
+        let sender = std::sync::Mutex::new(tx);
+        t.set_listener(move |event| {
+            if let Some(update) = RouteUpdate::from_event(event, 5) {
+                sender.lock().unwrap().send(update).unwrap();
+            }
+        });
+        t.add_subscription(filter("office/#"), 10, QoS::Level0).unwrap();
+        t.add_subscription(filter("office/#"), 11, QoS::Level0).unwrap();
+        t.remove_client(10);
+        rx.try_iter().for_each(|x| routes.apply(x));
+        let result = routes.route(&TopicName::try_from("office/desk").unwrap());
+        assert_eq!(result[0].node_id, 5);
+        t.remove_client(11);
+        rx.try_iter().for_each(|x| routes.apply(x));
+        assert!(routes.route(&TopicName::try_from("office/desk").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_acl() {
+        let topic = |x: &str| TopicName::try_from(x).unwrap();
+        let mut acl = Acl::default();
+        let rules = [
+            (Principal::All, AclAction::PublishSubscribe, AclPermission::Allow, "devices/%c/#"),
+            (Principal::All, AclAction::Subscribe, AclPermission::Allow, "users/%u/+/inbox"),
+            (Principal::User("alice".into()), AclAction::Subscribe, AclPermission::Allow, "a/b/#"),
+            (Principal::Group("ops".into()), AclAction::Publish, AclPermission::Allow, "#"),
+            (Principal::Group("ops".into()), AclAction::PublishSubscribe, AclPermission::Deny, "a/secret/#"),
+            (Principal::Client("sensor-1".into()), AclAction::Subscribe, AclPermission::Allow, "+/+"),
+        ];
+        for (principal, action, permission, pattern) in rules {
+            acl.add_rule(principal, action, permission, pattern).unwrap();
+        }
+        let err = acl.add_rule(Principal::All, AclAction::Publish, AclPermission::Allow, "$share/g/#");
+        assert!(matches!(err, Err(TopicFilterError::SharedSubscriptionsNotSupported)));
+
+        let sensor = Identity {
+            username: None,
+            client_identifier: "sensor-1".into(),
+            groups: Vec::new(),
+        };
+        assert!(acl.authorize_publish(&sensor, &topic("devices/sensor-1/temp")));
+        assert!(acl.authorize_publish(&sensor, &topic("devices/sensor-1")));
+        assert!(!acl.authorize_publish(&sensor, &topic("devices/sensor-2/temp")));
+        assert!(acl.authorize_subscribe(&sensor, &filter("devices/sensor-1/+")));
+        assert!(acl.authorize_subscribe(&sensor, &filter("$share/g/devices/sensor-1/#")));
+        assert!(!acl.authorize_subscribe(&sensor, &filter("devices/+/temp")));
+        // Without a username `%u` never matches
+        assert!(!acl.authorize_subscribe(&sensor, &filter("users/%u/x/inbox")));
+        assert!(acl.authorize_subscribe(&sensor, &filter("a/b")));
+        assert!(!acl.authorize_subscribe(&sensor, &filter("$SYS/b")));
+
+        let alice = Identity {
+            username: Some("alice".into()),
+            client_identifier: "phone".into(),
+            groups: vec!["ops".into()],
+        };
+        assert!(acl.authorize_subscribe(&alice, &filter("users/alice/+/inbox")));
+        assert!(acl.authorize_subscribe(&alice, &filter("users/alice/home/inbox")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("users/bob/home/inbox")));
+        // `a/#` is more than the allowed `a/b/#`
+        assert!(acl.authorize_subscribe(&alice, &filter("a/b/#")));
+        assert!(acl.authorize_subscribe(&alice, &filter("a/b/+/c")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("a/#")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("a/+/c")));
+        // Deny wins, also when the filter only partially overlaps the denied pattern
+        assert!(acl.authorize_publish(&alice, &topic("a/public")));
+        assert!(!acl.authorize_publish(&alice, &topic("a/secret/key")));
+        assert!(!acl.authorize_publish(&alice, &topic("a/secret")));
+        assert!(!acl.authorize_publish(&alice, &topic("$SYS/load")));
+        let mut acl = acl.clone();
+        acl.add_rule(Principal::User("alice".into()), AclAction::Subscribe, AclPermission::Allow, "a/#")
+            .unwrap();
+        assert!(acl.authorize_subscribe(&alice, &filter("a/public/+")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("a/+/c")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("a/#")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("+/secret")));
+        assert!(acl.authorize_subscribe(&alice, &filter("a/public/#")));
+
+        let open = Acl::new(AclPermission::Allow);
+        assert!(open.authorize_publish(&alice, &topic("anything")));
+        assert!(open.authorize_subscribe(&alice, &filter("#")));
+
+        // `+/#` covers `#` like `is_subset_of` says, `x/+/#` does not cover `x/#`
+        let mut acl = Acl::new(AclPermission::Deny);
+        acl.add_rule(Principal::All, AclAction::Subscribe, AclPermission::Allow, "+/#").unwrap();
+        acl.add_rule(Principal::All, AclAction::Subscribe, AclPermission::Allow, "x/+/#").unwrap();
+        assert!(filter("#").is_subset_of(&filter("+/#")));
+        assert!(acl.authorize_subscribe(&alice, &filter("#")));
+        assert!(acl.authorize_subscribe(&alice, &filter("x/#")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("$SYS/#")));
+        let mut acl = Acl::new(AclPermission::Deny);
+        acl.add_rule(Principal::All, AclAction::Subscribe, AclPermission::Allow, "x/+/#").unwrap();
+        assert!(!filter("x/#").is_subset_of(&filter("x/+/#")));
+        assert!(!acl.authorize_subscribe(&alice, &filter("x/#")));
+        assert!(acl.authorize_subscribe(&alice, &filter("x/y/#")));
     }
 
     #[test]
-    fn test_sync_speed() {
+    fn test_qos_downgrade() {
+        let topic = TopicName::try_from("home/bedroom/light").unwrap();
         let t = MqttTopicTree::default();
-        let s1 = TopicFilter::try_from("home/+/+".to_owned()).unwrap();
-        t.add_subscription(s1, 1, QoS::Level0);
-        let s2 = TopicFilter::try_from("home/#".to_owned()).unwrap();
-        t.add_subscription(s2, 2, QoS::Level0);
-        let s3 = TopicFilter::try_from("home/+/#".to_owned()).unwrap();
-        t.add_subscription(s3, 3, QoS::Level0);
-        let topic = TopicName::try_from("home/bedroom/light".to_owned()).unwrap();
-        let num_ops = 100000;
-        let t_start = Instant::now();
-        for _ in 0..num_ops {
-            let _ = t.get_subscriptions(&topic);
+        t.add_subscription(filter("home/#"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("home/+/light"), 1, QoS::Level2).unwrap();
+        t.add_subscription(filter("home/bedroom/+"), 2, QoS::Level1).unwrap();
+        t.add_subscription(filter("$share/g/home/#"), 2, QoS::Level2).unwrap();
+        t.add_subscription(filter("home/bedroom/light"), 3, QoS::Level2).unwrap();
+        assert_eq!(sorted(t.get_subscriptions_with_qos(&topic, QoS::Level1)), [
+            (1, QoS::Level0),
+            (1, QoS::Level1),
+            (2, QoS::Level1),
+            (2, QoS::Level1),
+            (3, QoS::Level1),
+        ]);
+        // The highest QoS of a client wins before it is downgraded
+        assert_eq!(sorted(t.get_subscriptions_deduplicated(&topic)), [
+            (1, QoS::Level2),
+            (2, QoS::Level2),
+            (3, QoS::Level2),
+        ]);
+        assert_eq!(sorted(t.get_subscriptions_deduplicated_with_qos(&topic, QoS::Level1)), [
+            (1, QoS::Level1),
+            (2, QoS::Level1),
+            (3, QoS::Level1),
+        ]);
+        assert_eq!(sorted(t.get_subscriptions_deduplicated_with_qos(&topic, QoS::Level0)), [
+            (1, QoS::Level0),
+            (2, QoS::Level0),
+            (3, QoS::Level0),
+        ]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let t = MqttTopicTree::default();
+        assert_eq!(t.stats().nodes, 0);
+        assert_eq!(t.dump(), "");
+        t.add_subscription(filter("home/#"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("home/+/light"), 2, QoS::Level1).unwrap();
+        t.add_subscription(filter("home/+/light"), 5, QoS::Level0).unwrap();
+        t.add_subscription(filter("$share/g/home/+/light"), 4, QoS::Level2).unwrap();
+        t.add_subscription(filter("$share/g/home/+/light"), 3, QoS::Level0).unwrap();
+        t.add_subscription(filter("office"), 1, QoS::Level1).unwrap();
+        let stats = t.stats();
+        assert_eq!(stats, TreeStats {
+            nodes: 5,
+            depth_histogram: vec![2, 2, 1],
+            single_level_wildcard_nodes: 1,
+            multi_level_wildcard_nodes: 1,
+            subscriptions: 6,
+            client_subscriptions: 4,
+            shared_subscriptions: 2,
+            shared_groups: 1,
+            clients: 5,
+            estimated_memory: stats.estimated_memory,
+        });
+        assert!(stats.estimated_memory > size_of::<TopicTree>());
+        let expected = "\
+home
+  # 1@0
+  +
+    light 2@1 5@0 $share/g[3@0 4@2]
+office 1@1
+";
+        assert_eq!(t.dump(), expected);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use crate::{MatchCounts, MetricsRecorder};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        #[derive(Default)]
+        struct Recorder {
+            lookups: Mutex<Vec<usize>>,
+            matches: Mutex<Vec<MatchCounts>>,
+            writes: Mutex<Vec<usize>>,
+            lock_waits: Mutex<usize>,
+        }
+
+        impl MetricsRecorder for Recorder {
+            fn record_lookup(&self, _: Duration, fan_out: usize) {
+                self.lookups.lock().unwrap().push(fan_out);
+            }
+
+            fn record_matches(&self, matches: &MatchCounts) {
+                self.matches.lock().unwrap().push(matches.clone());
+            }
+
+            fn record_lock_wait(&self, _: Duration) {
+                *self.lock_waits.lock().unwrap() += 1;
+            }
+
+            fn record_write(&self, _: Duration, operations: usize) {
+                self.writes.lock().unwrap().push(operations);
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let t = MqttTopicTree::default().with_recorder(recorder.clone());
+        t.add_subscription(filter("home/bedroom/light"), 1, QoS::Level0).unwrap();
+        t.add_subscription(filter("home/+/light"), 2, QoS::Level0).unwrap();
+        t.add_subscription(filter("home/+/#"), 3, QoS::Level0).unwrap();
+        t.add_subscription(filter("#"), 4, QoS::Level0).unwrap();
+        t.add_subscription(filter("$share/g/home/#"), 5, QoS::Level0).unwrap();
+        t.add_subscription(filter("$share/g/home/#"), 6, QoS::Level0).unwrap();
+        let mut batch = t.batch();
+        batch.remove_client(4);
+        batch.remove_subscription(filter("home/+/light"), 2);
+        batch.commit().unwrap();
+        // Empty batches are not recorded as writes
+        t.batch().commit().unwrap();
+        let subscribers = t.get_subscriptions(&TopicName::try_from("home/bedroom/light").unwrap());
+        assert_eq!(subscribers.len(), 3);
+        assert_eq!(*recorder.lookups.lock().unwrap(), [3]);
+        assert_eq!(*recorder.matches.lock().unwrap(), [MatchCounts {
+            exact: 1,
+            single_level_wildcard: 0,
+            multi_level_wildcard: 1,
+            shared: 1,
+        }]);
+        assert_eq!(*recorder.writes.lock().unwrap(), [1, 1, 1, 1, 1, 1, 2]);
+        assert_eq!(*recorder.lock_waits.lock().unwrap(), 8);
+        // Every lookup variant is recorded with its own fan out
+        let topic = TopicName::try_from("home/bedroom/light").unwrap();
+        t.add_subscription(filter("home/bedroom/+"), 1, QoS::Level1).unwrap();
+        assert_eq!(t.get_subscriptions_with_qos(&topic, QoS::Level0).len(), 4);
+        assert_eq!(t.get_subscriptions_deduplicated(&topic).len(), 3);
+        assert_eq!(t.get_subscriptions_deduplicated_with_qos(&topic, QoS::Level0).len(), 3);
+        assert_eq!(t.get_routed_subscriptions(&topic, &[]).len(), 3);
+        assert_eq!(*recorder.lookups.lock().unwrap(), [3, 4, 3, 3, 3]);
+        let matches = recorder.matches.lock().unwrap();
+        assert_eq!(matches.len(), 5);
+        assert!(matches[1..].iter().all(|x| x.single_level_wildcard == 1 && x.shared == 1));
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut t = TopicTree::default();
+        let subscriptions = [
+            ("home", 1, QoS::Level0),
+            ("home/#", 2, QoS::Level1),
+            ("home/+/light", 3, QoS::Level2),
+            ("$share/g/home/+/light", 4, QoS::Level0),
+            ("$share/g/home/+/light", 5, QoS::Level1),
+            ("house/#", 1, QoS::Level0),
+            ("#", 6, QoS::Level0),
+        ];
+        for (topic_filter, client_id, qos) in subscriptions {
+            t.add_subscription(filter(topic_filter), client_id, qos).unwrap();
+        }
+        let mut expected: Vec<_> = subscriptions
+            .iter()
+            .map(|(a, b, c)| (a.to_string(), *b, *c, filter(a).shared_group().map(String::from)))
+            .collect();
+        expected.sort();
+        assert_eq!(sorted_entries(t.iter().collect()), expected);
+        assert_eq!(sorted_entries(t.iter_under("").collect()), expected);
+        let home: Vec<_> = expected
+            .iter()
+            .filter(|x| filter(&x.0).filter_str().starts_with("home"))
+            .cloned()
+            .collect();
+        assert_eq!(home.len(), 5);
+        assert_eq!(home[0].3.as_deref(), Some("g"));
+        assert_eq!(sorted_entries(t.iter_under("home/").collect()), home);
+        assert_eq!(sorted_entries(t.iter_under("home").collect()), home);
+        assert_eq!(sorted_entries(t.iter_under("home/+").collect()), [&home[..2], &home[4..]].concat());
+        assert_eq!(sorted_entries(t.iter_under("home/#").collect()), [(
+            "home/#".to_owned(), 2, QoS::Level1, None
+        )]);
+        assert_eq!(t.iter_under("home/bedroom").count(), 0);
+        assert_eq!(t.iter_under("garden/+").count(), 0);
+
+        let t = MqttTopicTree::from_topic_tree(t);
+        assert_eq!(sorted_entries(t.iter().collect()), expected);
+        assert_eq!(sorted_entries(t.iter_under("home/").collect()), home);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_async_tree() {
+        use crate::AsyncMqttTopicTree;
+        let topic = TopicName::try_from("home/bedroom/light").unwrap();
+        let limits = TopicLimits {
+            max_subscriptions_per_client: 1,
+            ..Default::default()
+        };
+        let t = AsyncMqttTopicTree::new(MqttTopicTree::with_limits(limits)).unwrap();
+        // Writes that are sent together are committed together
+        let writes = (0..100).map(|i| {
+            let t = t.clone();
+            tokio::spawn(async move { t.add_subscription(filter("home/#"), i, QoS::Level0).await })
+        });
+        for write in writes.collect::<Vec<_>>() {
+            write.await.unwrap().unwrap();
+        }
+        assert_eq!(t.get_subscriptions(&topic).len(), 100);
+        let res = t.add_subscription(filter("home/+/light"), 1, QoS::Level0).await;
+        assert!(matches!(res, Err(WriteError::Rejected(SubscriptionError::TooManySubscriptions { .. }))));
+        // A removal frees up quota for the writes that are sent after it
+        let (removed, added) = tokio::join!(
+            t.remove_client(1),
+            t.add_subscription(filter("home/+/light"), 1, QoS::Level1),
+        );
+        removed.unwrap();
+        added.unwrap();
+        t.remove_subscription(filter("home/#"), 2).await.unwrap();
+        let subscribers = t.tree().get_subscriptions(&topic);
+        assert_eq!(subscribers.len(), 99);
+        assert!(subscribers.contains(&Subscriber { client_id: 1, qos: QoS::Level1 }));
+        // Writes wait for room in a full queue instead of failing
+        let t = AsyncMqttTopicTree::with_capacity(MqttTopicTree::default(), 1).unwrap();
+        let writes = (0..100).map(|i| {
+            let t = t.clone();
+            tokio::spawn(async move { t.add_subscription(filter("home/#"), i, QoS::Level0).await })
+        });
+        for write in writes.collect::<Vec<_>>() {
+            write.await.unwrap().unwrap();
+        }
+        assert_eq!(t.get_subscriptions(&topic).len(), 100);
+    }
+
+    #[test]
+    fn test_epochs() {
+        let topic = TopicName::try_from("home/bedroom/light").unwrap();
+        let t = MqttTopicTree::default();
+        let reader = t.clone();
+        let start = t.visible_epoch();
+        let first = t.add_subscription(filter("home/#"), 1, QoS::Level0).unwrap();
+        assert!(first > start);
+        assert!(reader.is_visible(first));
+        // A deferred commit is only visible once it is published
+        let mut batch = t.batch();
+        batch.add_subscription(filter("home/+/light"), 2, QoS::Level0).unwrap();
+        let deferred = batch.commit_deferred().unwrap();
+        assert!(deferred > first);
+        assert!(!reader.is_visible(deferred));
+        assert_eq!(reader.visible_epoch(), first);
+        assert_eq!(reader.get_subscriptions(&topic).len(), 1);
+        reader.wait_visible(deferred);
+        assert!(reader.is_visible(deferred));
+        assert_eq!(reader.get_subscriptions(&topic).len(), 2);
+        // An empty batch returns the epoch of the last write
+        assert_eq!(t.batch().commit().unwrap(), deferred);
+        // The next commit publishes the deferred writes as well
+        let mut batch = t.batch();
+        batch.remove_client(1);
+        let removed = batch.commit_deferred().unwrap();
+        assert_eq!(reader.get_subscriptions(&topic).len(), 2);
+        t.publish();
+        assert!(reader.is_visible(removed));
+        assert_eq!(reader.get_subscriptions(&topic).len(), 1);
+
+        // Deferred commits that are not published yet count against the quotas
+        let limits = TopicLimits {
+            max_subscriptions_per_client: 1,
+            max_total_nodes: 3,
+            ..Default::default()
+        };
+        let t = MqttTopicTree::with_limits(limits);
+        let mut batch = t.batch();
+        batch.add_subscription(filter("a/b"), 1, QoS::Level0).unwrap();
+        batch.commit_deferred().unwrap();
+        let mut batch = t.batch();
+        assert!(matches!(
+            batch.add_subscription(filter("a/c"), 1, QoS::Level0),
+            Err(SubscriptionError::TooManySubscriptions { max: 1 })
+        ));
+        assert!(matches!(
+            batch.add_subscription(filter("x/y"), 2, QoS::Level0),
+            Err(SubscriptionError::TooManyNodes { max: 3 })
+        ));
+        batch.add_subscription(filter("z"), 2, QoS::Level0).unwrap();
+        batch.commit_deferred().unwrap();
+        t.publish();
+        assert_eq!(t.iter().count(), 2);
+        t.add_subscription(filter("a/b"), 1, QoS::Level2).unwrap();
+    }
+
+    #[cfg(feature = "rcu")]
+    #[test]
+    fn test_rcu_versions_share_nodes() {
+        fn cmd_node<'a>(tree: &'a TopicTree, device: &str) -> &'a crate::topic_tree::TopicNode {
+            let mut node = &tree.root_node;
+            for topic_level in ["fleet", device, "cmd"] {
+                node = &node.sub_nodes[tree.interner.get(topic_level).unwrap()];
+            }
+            node
+        }
+        let t = MqttTopicTree::default();
+        for i in 0..1000 {
+            t.add_subscription(filter(&format!("fleet/dev{i}/cmd")), i, QoS::Level0).unwrap();
+        }
+        t.add_subscription(filter("fleet/dev1/cmd"), 5000, QoS::Level0).unwrap();
+        let old = t.published_tree();
+        t.add_subscription(filter("fleet/dev0/status"), 0, QoS::Level0).unwrap();
+        t.remove_client(1).unwrap();
+        let new = t.published_tree();
+        // A reader keeps the version it loaded
+        let topic = TopicName::try_from("fleet/dev1/cmd").unwrap();
+        assert_eq!(old.get_subscriptions(&topic).len(), 2);
+        assert_eq!(new.get_subscriptions(&topic).len(), 1);
+        let topic = TopicName::try_from("fleet/dev0/status").unwrap();
+        assert!(old.get_subscriptions(&topic).is_empty());
+        // Only the nodes the writes changed are copied
+        assert!(!cmd_node(&old, "dev1").content.ptr_eq(&cmd_node(&new, "dev1").content));
+        assert!(cmd_node(&old, "dev2").content.ptr_eq(&cmd_node(&new, "dev2").content));
+    }
+
+    #[test]
+    fn test_sharded_tree() {
+        use crate::ShardedMqttTopicTree;
+        let filters = ["home/#", "home/+/light", "garden/a/b", "#", "+/+/+", "+", "$SYS/#"];
+        let topics = ["home/bedroom/light", "home", "garden/a/b", "$SYS/uptime", "office"];
+        let mut t = TopicTree::default();
+        let sharded = ShardedMqttTopicTree::new(4);
+        assert_eq!(sharded.shard_count(), 5);
+        for (i, topic_filter) in filters.iter().enumerate() {
+            for client_id in 0..3 {
+                let qos = QoS::try_from(((i + client_id as usize) % 3) as u8).unwrap();
+                t.add_subscription(filter(topic_filter), client_id, qos).unwrap();
+                sharded.add_subscription(filter(topic_filter), client_id, qos).unwrap();
+            }
+        }
+        sharded.remove_subscription(filter("home/#"), 1).unwrap();
+        t.remove_subscription(filter("home/#"), 1);
+        sharded.remove_client(2).unwrap();
+        t.remove_client(2);
+        sharded.remove_client(3).unwrap();
+        for topic in topics {
+            let topic = TopicName::try_from(topic).unwrap();
+            assert_eq!(sorted(sharded.get_subscriptions(&topic)), sorted(t.get_subscriptions(&topic)));
         }
-        let t_end = Instant::now();
-        let t_delta = (t_end - t_start).as_nanos() / num_ops as u128;
-        println!("MqttTopicTree Lookup took {t_delta} ns per iteration");
+        assert_eq!(sorted_entries(sharded.iter().collect()), sorted_entries(t.iter().collect()));
+        let (stats, expected) = (sharded.stats(), t.stats());
+        assert_eq!(stats.nodes, expected.nodes);
+        assert_eq!(stats.depth_histogram, expected.depth_histogram);
+        assert_eq!(stats.subscriptions, expected.subscriptions);
+        assert_eq!(stats.clients, expected.clients);
+        // A shared group lives in a single shard and still delivers to one member
+        let sharded = ShardedMqttTopicTree::new(4);
+        sharded.add_subscription(filter("$share/g/home/#"), 1, QoS::Level0).unwrap();
+        sharded.add_subscription(filter("$share/g/home/#"), 2, QoS::Level0).unwrap();
+        let topic = TopicName::try_from("home/bedroom/light").unwrap();
+        assert_eq!(sharded.get_subscriptions(&topic).len(), 1);
+        // The quotas apply to all shards together
+        let limits = TopicLimits {
+            max_subscriptions_per_client: 2,
+            max_total_nodes: 6,
+            max_levels: 3,
+            ..Default::default()
+        };
+        let sharded = ShardedMqttTopicTree::with_limits(4, limits);
+        sharded.add_subscription(filter("a/b"), 1, QoS::Level0).unwrap();
+        sharded.add_subscription(filter("+/b"), 1, QoS::Level0).unwrap();
+        sharded.add_subscription(filter("a/b"), 1, QoS::Level1).unwrap();
+        let res = sharded.add_subscription(filter("c/d"), 1, QoS::Level0);
+        assert!(matches!(res, Err(WriteError::Rejected(SubscriptionError::TooManySubscriptions { .. }))));
+        let res = sharded.add_subscription(filter("x/y/z"), 2, QoS::Level0);
+        assert!(matches!(res, Err(WriteError::Rejected(SubscriptionError::TooManyNodes { max: 6 }))));
+        let res = sharded.add_subscription(filter("a/b/c/d"), 2, QoS::Level0);
+        assert!(matches!(res, Err(WriteError::Rejected(SubscriptionError::TooManyLevels { .. }))));
+        // Removals free up the quotas again
+        sharded.remove_subscription(filter("a/b"), 1).unwrap();
+        sharded.add_subscription(filter("c/d"), 1, QoS::Level0).unwrap();
+        sharded.remove_client(1).unwrap();
+        sharded.add_subscription(filter("x/y/z"), 2, QoS::Level0).unwrap();
+        sharded.add_subscription(filter("q/r"), 3, QoS::Level0).unwrap();
+        let res = sharded.add_subscription(filter("s/t"), 3, QoS::Level0);
+        assert!(matches!(res, Err(WriteError::Rejected(SubscriptionError::TooManyNodes { .. }))));
+        // A batch that the quotas reject is not applied to any shard
+        let limits = TopicLimits {
+            max_subscriptions_per_client: 2,
+            ..Default::default()
+        };
+        let sharded = ShardedMqttTopicTree::with_limits(4, limits);
+        let mut batch = sharded.batch();
+        batch.add_subscription(filter("a/b"), 1, QoS::Level0).unwrap();
+        batch.add_subscription(filter("+/b"), 1, QoS::Level0).unwrap();
+        batch.add_subscription(filter("c/d"), 1, QoS::Level0).unwrap();
+        let res = batch.commit();
+        assert!(matches!(res, Err(WriteError::Rejected(SubscriptionError::TooManySubscriptions { .. }))));
+        assert_eq!(sharded.iter().count(), 0);
+        let mut batch = sharded.batch();
+        batch.add_subscription(filter("a/b"), 1, QoS::Level0).unwrap();
+        batch.add_subscription(filter("+/b"), 1, QoS::Level0).unwrap();
+        let epochs = batch.commit().unwrap();
+        assert!(sharded.is_visible(&epochs));
+        sharded.wait_visible(&epochs);
+        assert_eq!(sharded.get_subscriptions(&TopicName::try_from("a/b").unwrap()).len(), 2);
+        // Removing the client frees its quota in every shard
+        let mut batch = sharded.batch();
+        batch.remove_client(1);
+        batch.add_subscription(filter("x/y"), 2, QoS::Level0).unwrap();
+        batch.commit().unwrap();
+        sharded.add_subscription(filter("c/d"), 1, QoS::Level0).unwrap();
+        sharded.add_subscription(filter("e/f"), 1, QoS::Level0).unwrap();
+        assert_eq!(sharded.stats().clients, 2);
+    }
+
+    #[cfg(feature = "intern")]
+    #[test]
+    fn test_interned_levels() {
+        let mut t = TopicTree::default();
+        for device in 0..100 {
+            let topic_filter = filter(&format!("devices/{device}/status"));
+            t.add_subscription(topic_filter, device, QoS::Level0).unwrap();
+        }
+        t.add_subscription(filter("devices/+/status"), 100, QoS::Level0).unwrap();
+        // Every level is stored once, also by a clone
+        let copy = t.clone();
+        assert_eq!(copy.interner.len(), 102);
+        let status = *t.interner.get("status").unwrap();
+        assert!(t.interner.shares_name(&copy.interner, &status));
+        t.add_subscription(filter("devices/new/status"), 101, QoS::Level0).unwrap();
+        assert_eq!(copy.interner.len(), 103);
+        assert!(copy.interner.get("new").is_none());
+        let topic = TopicName::try_from("devices/7/status").unwrap();
+        assert_eq!(copy.get_subscriptions(&topic).len(), 2);
+        // A level stays in the table while a clone still uses it
+        let other = t.clone();
+        t.remove_client(101);
+        assert_eq!(t.interner.len(), 103);
+        drop(other);
+        assert_eq!(t.interner.len(), 102);
+        drop(copy);
+        t.add_subscription(filter("devices/new/status"), 101, QoS::Level0).unwrap();
+        // A level that was never stored only matches wildcards
+        let topic = TopicName::try_from("devices/unknown/status").unwrap();
+        assert_eq!(t.get_subscriptions(&topic), vec![Subscriber { client_id: 100, qos: QoS::Level0 }]);
+        assert!(t.remove_subscription(filter("devices/7/status"), 7));
+        assert!(!t.remove_subscription(filter("devices/unknown/status"), 7));
+        let mut buf = Vec::new();
+        t.write_snapshot(&mut buf).unwrap();
+        let restored = TopicTree::read_snapshot(buf.as_slice()).unwrap();
+        assert_eq!(restored.iter().count(), 101);
+        assert_eq!(restored.dump(), t.dump());
+        // Levels are removed from the table with the last node that uses them
+        assert_eq!(t.interner.len(), 102);
+        t.remove_client(101);
+        assert_eq!(t.interner.len(), 101);
+        for device in 0..100 {
+            t.remove_client(device);
+        }
+        assert_eq!(t.interner.len(), 2);
+        assert!(t.remove_subscription(filter("devices/+/status"), 100));
+        assert_eq!(t.interner.len(), 0);
+        // Churning through random levels does not grow the table
+        for i in 0..1000 {
+            let topic_filter = filter(&format!("churn/level{i}"));
+            t.add_subscription(topic_filter.clone(), 1, QoS::Level0).unwrap();
+            t.remove_subscription(topic_filter, 1);
+        }
+        assert_eq!(t.interner.len(), 0);
+        t.add_subscription(filter("devices/a/status"), 1, QoS::Level0).unwrap();
+        let topic = TopicName::try_from("devices/a/status").unwrap();
+        assert_eq!(t.get_subscriptions(&topic).len(), 1);
+        assert_eq!(t.dump(), "devices\n  a\n    status 1@0\n");
     }
 
     #[test]
@@ -87,11 +1548,8 @@ mod tests {
         // is_sync(t.clone());
     }
 
-    fn is_sync<T: Sync>(a: T) {
-        return;
-    }
+    #[allow(dead_code)]
+    fn is_sync<T: Sync>(_a: T) {}
 
-    fn is_send<T: Send>(a: T) {
-        return;
-    }
+    fn is_send<T: Send>(_a: T) {}
 }
@@ -0,0 +1,128 @@
+use crate::TopicFilter;
+use std::fmt;
+
+/// The MQTT 5 reason code for a subscription that would exceed a server quota
+pub const REASON_QUOTA_EXCEEDED: u8 = 0x97;
+
+/// Limits on the shape and amount of subscriptions a TopicTree will accept, these protect the tree
+/// from clients that try to bloat it with very deep or very many filters.
+/// The default limits only cap a filter at the `u16::MAX` bytes the protocol allows.
+#[derive(Clone, Debug)]
+pub struct TopicLimits {
+    /// The maximum number of levels in a topic filter, excluding the `$share/<group>` prefix
+    pub max_levels: usize,
+    /// The maximum length of a topic filter in bytes
+    pub max_bytes: usize,
+    /// The maximum length of a single topic level in bytes
+    pub max_level_length: usize,
+    /// The maximum number of subscriptions a single client can hold
+    pub max_subscriptions_per_client: usize,
+    /// The maximum number of nodes in the tree, every level of a stored filter is a node
+    pub max_total_nodes: usize,
+}
+
+impl Default for TopicLimits {
+    fn default() -> Self {
+        Self {
+            max_levels: usize::MAX,
+            max_bytes: u16::MAX as usize,
+            max_level_length: usize::MAX,
+            max_subscriptions_per_client: usize::MAX,
+            max_total_nodes: usize::MAX,
+        }
+    }
+}
+
+impl TopicLimits {
+    /// Checks the shape of a filter, which does not depend on the contents of the tree
+    pub(crate) fn check_filter(&self, topic_filter: &TopicFilter) -> Result<(), SubscriptionError> {
+        if topic_filter.length > self.max_levels {
+            return Err(SubscriptionError::TooManyLevels {
+                levels: topic_filter.length,
+                max: self.max_levels,
+            });
+        };
+        if topic_filter.orig_str.len() > self.max_bytes {
+            return Err(SubscriptionError::TooLong {
+                bytes: topic_filter.orig_str.len(),
+                max: self.max_bytes,
+            });
+        };
+        for i in 0..topic_filter.length {
+            if topic_filter.get_part(i).unwrap().len() > self.max_level_length {
+                return Err(SubscriptionError::LevelTooLong {
+                    level: i,
+                    max: self.max_level_length,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a new subscription of a client that already holds `client_subscriptions`, in a tree
+    /// that will have `total_nodes` nodes once it is added
+    pub(crate) fn check_quota(
+        &self,
+        client_subscriptions: usize,
+        total_nodes: usize,
+    ) -> Result<(), SubscriptionError> {
+        if client_subscriptions >= self.max_subscriptions_per_client {
+            return Err(SubscriptionError::TooManySubscriptions {
+                max: self.max_subscriptions_per_client,
+            });
+        };
+        if total_nodes > self.max_total_nodes {
+            return Err(SubscriptionError::TooManyNodes {
+                max: self.max_total_nodes,
+            });
+        };
+        Ok(())
+    }
+}
+
+/// The reason a subscription was rejected by the TopicTree
+#[derive(Clone, Debug)]
+pub enum SubscriptionError {
+    TooManyLevels { levels: usize, max: usize },
+    TooLong { bytes: usize, max: usize },
+    LevelTooLong { level: usize, max: usize },
+    TooManySubscriptions { max: usize },
+    TooManyNodes { max: usize },
+}
+
+impl SubscriptionError {
+    /// The MQTT 5 reason code to send back to the client
+    pub fn reason_code(&self) -> u8 {
+        match self {
+            SubscriptionError::TooManyLevels { .. }
+            | SubscriptionError::TooLong { .. }
+            | SubscriptionError::LevelTooLong { .. } => crate::topic::REASON_TOPIC_FILTER_INVALID,
+            SubscriptionError::TooManySubscriptions { .. }
+            | SubscriptionError::TooManyNodes { .. } => REASON_QUOTA_EXCEEDED,
+        }
+    }
+}
+
+impl fmt::Display for SubscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubscriptionError::TooManyLevels { levels, max } => {
+                write!(f, "topic filter has {levels} levels, the maximum is {max}")
+            }
+            SubscriptionError::TooLong { bytes, max } => {
+                write!(f, "topic filter is {bytes} bytes long, the maximum is {max}")
+            }
+            SubscriptionError::LevelTooLong { level, max } => {
+                write!(f, "topic filter level {level} is longer than {max} bytes")
+            }
+            SubscriptionError::TooManySubscriptions { max } => {
+                write!(f, "client already holds the maximum of {max} subscriptions")
+            }
+            SubscriptionError::TooManyNodes { max } => {
+                write!(f, "topic tree already holds the maximum of {max} nodes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubscriptionError {}
@@ -0,0 +1,60 @@
+//! Hooks for recording the latency and fan-out of a MqttTopicTree.
+//!
+//! The tree reports to a [`MetricsRecorder`], which forwards the values to whatever metrics system
+//! is in use. Every method has an empty default, so a recorder only implements what it exports.
+
+use crate::topic_tree::SubscriptionInfo;
+use std::time::Duration;
+
+/// The number of subscribers a lookup matched, by the kind of filter that matched them. A filter
+/// with both wildcards counts as a multi level wildcard match.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MatchCounts {
+    pub exact: usize,
+    pub single_level_wildcard: usize,
+    pub multi_level_wildcard: usize,
+    /// Subscribers picked from a shared group, regardless of the filter of the group
+    pub shared: usize,
+}
+
+pub trait MetricsRecorder: Send + Sync {
+    /// A lookup took `duration` and returned `fan_out` subscribers
+    fn record_lookup(&self, duration: Duration, fan_out: usize) {
+        let _ = (duration, fan_out);
+    }
+
+    fn record_matches(&self, matches: &MatchCounts) {
+        let _ = matches;
+    }
+
+    /// A writer waited `duration` for the write lock
+    fn record_lock_wait(&self, duration: Duration) {
+        let _ = duration;
+    }
+
+    /// A publish waited `duration` for the readers to leave the old copy of the tree
+    fn record_publish_wait(&self, duration: Duration) {
+        let _ = duration;
+    }
+
+    /// A batch of `operations` writes was committed in `duration`, including the publish
+    fn record_write(&self, duration: Duration, operations: usize) {
+        let _ = (duration, operations);
+    }
+}
+
+impl MatchCounts {
+    /// Counts the subscribers of a SubscriptionInfo by the kind of its filter, without picking
+    /// clients from the shared groups
+    pub(crate) fn count(&mut self, levels: &[&str], sub_info: &SubscriptionInfo) {
+        let clients = sub_info.client_subscriptions.len();
+        if levels.last() == Some(&"#") {
+            self.multi_level_wildcard += clients;
+        } else if levels.contains(&"+") {
+            self.single_level_wildcard += clients;
+        } else {
+            self.exact += clients;
+        }
+        self.shared += sub_info.shared_subscriptions.len();
+    }
+}
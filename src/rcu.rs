@@ -0,0 +1,163 @@
+//! The backend of a MqttTopicTree with the `rcu` feature, which shares the nodes of the tree
+//! between versions instead of keeping two copies.
+//!
+//! The writer holds the only copy of the tree that is changed. The parts of its nodes are
+//! reference counted, and a part that a published version still uses is copied before it is
+//! changed, so a write only copies the nodes on the path to the filter it changes. The sub nodes
+//! of a node are kept in a persistent map, so copying a node with many sub nodes only copies the
+//! part of the map that leads to the changed one. Publishing swaps the version readers load
+//! atomically, readers never lock and keep the version they loaded alive for as long as they use
+//! it.
+//!
+//! Compared to left-right this keeps one copy of the tree instead of two, and a publish never
+//! waits for readers. In return every lookup updates an atomic reference count, and every write
+//! allocates the nodes it copies. Run the benchmarks with and without the feature to compare.
+
+use crate::TopicTree;
+use crate::sync::{CHECKED, TopicTreeOperations};
+use arc_swap::{ArcSwap, Guard};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// A part of a node that versions of the tree share, changing it copies it unless no other
+/// version uses it
+#[derive(Default)]
+pub(crate) struct CowArc<T>(Arc<T>);
+
+impl<T> CowArc<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self(Arc::new(value))
+    }
+
+    /// Whether both versions use the same part
+    #[cfg(test)]
+    pub(crate) fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T> Clone for CowArc<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Deref for CowArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Clone> DerefMut for CowArc<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+impl<T> From<T> for CowArc<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CowArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Queues operations on the tree of the writer and publishes it, mirroring the WriteHandle of
+/// left-right
+pub(crate) struct WriteHandle {
+    topic_tree: TopicTree,
+    operations: Vec<TopicTreeOperations>,
+    published: Arc<ArcSwap<TopicTree>>,
+}
+
+impl WriteHandle {
+    pub(crate) fn new(topic_tree: TopicTree) -> Self {
+        let published = Arc::new(ArcSwap::from_pointee(topic_tree.published_copy()));
+        Self {
+            topic_tree,
+            operations: Vec::new(),
+            published,
+        }
+    }
+
+    pub(crate) fn append(&mut self, operation: TopicTreeOperations) {
+        self.operations.push(operation);
+    }
+
+    /// Applies the queued operations and makes the result visible to readers
+    pub(crate) fn publish(&mut self) {
+        if self.operations.is_empty() {
+            return;
+        }
+        for operation in self.operations.drain(..) {
+            operation.apply_to(&mut self.topic_tree).expect(CHECKED);
+        }
+        self.published.store(Arc::new(self.topic_tree.published_copy()));
+    }
+
+    /// Reads the published version, like the readers do
+    pub(crate) fn enter(&self) -> Option<ReadGuard<'_>> {
+        Some(ReadGuard::load(&self.published))
+    }
+
+    pub(crate) fn factory(&self) -> ReadHandleFactory {
+        ReadHandleFactory {
+            published: self.published.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ReadHandleFactory {
+    published: Arc<ArcSwap<TopicTree>>,
+}
+
+impl ReadHandleFactory {
+    pub(crate) fn handle(&self) -> ReadHandle {
+        ReadHandle {
+            published: self.published.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ReadHandle {
+    published: Arc<ArcSwap<TopicTree>>,
+}
+
+impl ReadHandle {
+    pub(crate) fn enter(&self) -> Option<ReadGuard<'_>> {
+        Some(ReadGuard::load(&self.published))
+    }
+}
+
+/// Keeps the version of the tree that was published when it was loaded
+pub(crate) struct ReadGuard<'a> {
+    guard: Guard<Arc<TopicTree>>,
+    _handle: PhantomData<&'a ()>,
+}
+
+impl ReadGuard<'_> {
+    fn load(published: &ArcSwap<TopicTree>) -> Self {
+        Self {
+            guard: published.load(),
+            _handle: PhantomData,
+        }
+    }
+}
+
+impl Deref for ReadGuard<'_> {
+    type Target = TopicTree;
+
+    fn deref(&self) -> &TopicTree {
+        &self.guard
+    }
+}
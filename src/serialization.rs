@@ -0,0 +1,70 @@
+//! Serde support, enabled with the `serde` feature.
+//!
+//! Topics serialize as their original strings and are validated again when they are deserialized.
+//! A TopicTree serializes as a flat list of its subscriptions, deserializing it adds them to a new
+//! tree with the default limits.
+
+use crate::{ClientId, QoS, TopicFilter, TopicName, TopicTree};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+impl Serialize for TopicName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TopicName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        TopicName::try_from(value).map_err(D::Error::custom)
+    }
+}
+
+impl Serialize for TopicFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for TopicFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        TopicFilter::try_from(value).map_err(D::Error::custom)
+    }
+}
+
+/// A single entry in the serialized form of a TopicTree
+#[derive(Serialize, Deserialize)]
+struct SubscriptionEntry {
+    topic_filter: TopicFilter,
+    client_id: ClientId,
+    qos: QoS,
+}
+
+impl Serialize for TopicTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries: Vec<SubscriptionEntry> = self
+            .subscriptions()
+            .map(|(topic_filter, subscriber)| SubscriptionEntry {
+                topic_filter,
+                client_id: subscriber.client_id,
+                qos: subscriber.qos,
+            })
+            .collect();
+        entries.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TopicTree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entries = Vec::<SubscriptionEntry>::deserialize(deserializer)?;
+        let mut topic_tree = TopicTree::default();
+        for entry in entries {
+            topic_tree
+                .add_subscription(entry.topic_filter, entry.client_id, entry.qos)
+                .map_err(D::Error::custom)?;
+        }
+        Ok(topic_tree)
+    }
+}
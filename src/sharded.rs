@@ -0,0 +1,371 @@
+//! A MqttTopicTree that is split into shards, so writes to different shards don't wait for each
+//! other.
+//!
+//! Filters are assigned to a shard by their first level, and filters that start with a wildcard
+//! are kept in a shard of their own. A topic can only match the filters in the shard of its first
+//! level and the wildcard shard, so a lookup reads at most two shards.
+//!
+//! The shards check the shape of every filter, while the quotas are kept for the tree as a whole.
+//! Writes hold the lock of their shard and briefly take a lock that is shared by all shards to
+//! check and update the quotas. A write returns the epoch it got in every shard it wrote to, and
+//! a batch that spans several shards is applied to each of them at once, so a reader can see the
+//! writes of one shard before those of another.
+
+use crate::sync::TopicTreeOperations::{self, AddSubscription, RemoveClient, RemoveSubscription};
+use crate::{
+    Batch, ClientId, Epoch, MqttTopicTree, QoS, Subscriber, SubscriptionError, TopicFilter,
+    TopicLimits, TopicName, TopicTree, TreeStats, WriteError,
+};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+
+/// The number of shards of a default ShardedMqttTopicTree, not counting the wildcard shard
+pub const DEFAULT_SHARDS: usize = 16;
+
+#[derive(Clone)]
+pub struct ShardedMqttTopicTree {
+    shards: Vec<MqttTopicTree>,
+    wildcard_shard: MqttTopicTree,
+    quotas: Arc<Mutex<ShardQuotas>>,
+}
+
+/// Identifies a write to a ShardedMqttTopicTree by the epoch it got in every shard it wrote to.
+/// See [`ShardedMqttTopicTree::wait_visible`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShardEpochs(Vec<(usize, Epoch)>);
+
+/// The subscriptions of every client and the nodes of every shard together
+struct ShardQuotas {
+    client_subscriptions: HashMap<ClientId, usize>,
+    nodes: usize,
+    limits: TopicLimits,
+}
+
+/// The nodes of a shard and the subscriptions of the clients a batch writes to
+#[derive(Default)]
+struct ShardCounts {
+    nodes: usize,
+    client_subscriptions: HashMap<ClientId, usize>,
+}
+
+impl ShardCounts {
+    fn read(tree: &TopicTree, clients: impl Iterator<Item = ClientId>) -> Self {
+        let client_subscriptions = clients
+            .map(|x| (x, tree.client_subscriptions.get(&x).copied().unwrap_or(0)))
+            .collect();
+        Self {
+            nodes: tree.nodes,
+            client_subscriptions,
+        }
+    }
+}
+
+impl ShardQuotas {
+    fn reserve(
+        &mut self,
+        client_id: ClientId,
+        new_nodes: usize,
+        reserved: &mut ShardCounts,
+    ) -> Result<(), SubscriptionError> {
+        let client_subscriptions = self.client_subscriptions.get(&client_id).copied();
+        self.limits
+            .check_quota(client_subscriptions.unwrap_or(0), self.nodes + new_nodes)?;
+        *self.client_subscriptions.entry(client_id).or_insert(0) += 1;
+        self.nodes += new_nodes;
+        *reserved.client_subscriptions.entry(client_id).or_insert(0) += 1;
+        reserved.nodes += new_nodes;
+        Ok(())
+    }
+
+    /// Replaces what was reserved for the writes to a shard by what they changed
+    fn settle(&mut self, reserved: &ShardCounts, before: &ShardCounts, after: &ShardCounts) {
+        self.nodes = self.nodes + after.nodes - before.nodes - reserved.nodes;
+        for (client_id, after) in after.client_subscriptions.iter() {
+            let before = before.client_subscriptions[client_id];
+            let reserved = reserved.client_subscriptions.get(client_id).copied();
+            let count = self.client_subscriptions.entry(*client_id).or_insert(0);
+            *count = *count + after - before - reserved.unwrap_or(0);
+            if *count == 0 {
+                self.client_subscriptions.remove(client_id);
+            }
+        }
+    }
+}
+
+impl Default for ShardedMqttTopicTree {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
+}
+
+impl ShardedMqttTopicTree {
+    /// Creates a tree with `shards` shards for filters that start with a literal level, and one
+    /// for filters that start with a wildcard
+    pub fn new(shards: usize) -> Self {
+        Self::with_limits(shards, TopicLimits::default())
+    }
+
+    /// The quotas apply to all shards together, a client can hold `max_subscriptions_per_client`
+    /// subscriptions in the whole tree
+    pub fn with_limits(shards: usize, limits: TopicLimits) -> Self {
+        assert!(shards > 0, "a sharded topic tree needs at least one shard");
+        let shard_limits = TopicLimits {
+            max_subscriptions_per_client: usize::MAX,
+            max_total_nodes: usize::MAX,
+            ..limits.clone()
+        };
+        let quotas = ShardQuotas {
+            client_subscriptions: HashMap::new(),
+            nodes: 0,
+            limits,
+        };
+        Self {
+            shards: (0..shards)
+                .map(|_| MqttTopicTree::with_limits(shard_limits.clone()))
+                .collect(),
+            wildcard_shard: MqttTopicTree::with_limits(shard_limits),
+            quotas: Arc::new(Mutex::new(quotas)),
+        }
+    }
+
+    /// The number of shards, including the wildcard shard
+    pub fn shard_count(&self) -> usize {
+        self.shards.len() + 1
+    }
+
+    /// Starts a set of writes that is committed together, see [`ShardedBatch`]
+    pub fn batch(&self) -> ShardedBatch<'_> {
+        ShardedBatch {
+            tree: self,
+            operations: BTreeMap::new(),
+        }
+    }
+
+    /// The subscription is visible to readers once this returns
+    pub fn add_subscription(
+        &self,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+        qos: QoS,
+    ) -> Result<ShardEpochs, WriteError> {
+        let mut batch = self.batch();
+        batch.add_subscription(topic_filter, client_id, qos)?;
+        batch.commit()
+    }
+
+    pub fn remove_subscription(
+        &self,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+    ) -> Result<ShardEpochs, WriteError> {
+        let mut batch = self.batch();
+        batch.remove_subscription(topic_filter, client_id);
+        batch.commit()
+    }
+
+    /// Removes all subscriptions of a client from every shard, shards without a subscription of
+    /// the client are not written to
+    pub fn remove_client(&self, client_id: ClientId) -> Result<ShardEpochs, WriteError> {
+        let mut batch = self.batch();
+        batch.remove_client(client_id);
+        batch.commit()
+    }
+
+    /// Whether every shard the write went to has published it
+    pub fn is_visible(&self, epochs: &ShardEpochs) -> bool {
+        epochs.0.iter().all(|(shard, epoch)| self.shard(*shard).is_visible(*epoch))
+    }
+
+    /// Makes sure the write is visible to every reader, see [`MqttTopicTree::wait_visible`]
+    pub fn wait_visible(&self, epochs: &ShardEpochs) {
+        for (shard, epoch) in epochs.0.iter() {
+            self.shard(*shard).wait_visible(*epoch);
+        }
+    }
+
+    /// See [`MqttTopicTree::get_subscriptions`], the subscribers of the shard of the first level
+    /// are followed by those of the wildcard shard
+    pub fn get_subscriptions(&self, publish_topic: &TopicName) -> Vec<Subscriber> {
+        let first_level = publish_topic.get_part(0).unwrap();
+        let shard = &self.shards[self.shard_index(first_level)];
+        let mut results = shard.get_subscriptions(publish_topic);
+        // Wildcards at the first level don't match topics that start with '$'
+        if !first_level.starts_with('$') {
+            results.extend(self.wildcard_shard.get_subscriptions(publish_topic));
+        }
+        results
+    }
+
+    /// See [`MqttTopicTree::iter`], the shards are read one after the other
+    pub fn iter(&self) -> impl Iterator<Item = (TopicFilter, ClientId, QoS, Option<String>)> + '_ {
+        self.shards.iter().chain([&self.wildcard_shard]).flat_map(|x| x.iter())
+    }
+
+    /// The statistics of all shards together, the shards are read one after the other
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats::default();
+        for shard in self.shards.iter().chain([&self.wildcard_shard]) {
+            stats.add(&shard.stats());
+        }
+        stats.clients = self.quotas.lock().client_subscriptions.len();
+        stats
+    }
+
+    /// The shard with the index, the wildcard shard comes after the others
+    fn shard(&self, index: usize) -> &MqttTopicTree {
+        self.shards.get(index).unwrap_or(&self.wildcard_shard)
+    }
+
+    fn filter_shard(&self, topic_filter: &TopicFilter) -> usize {
+        match topic_filter.get_part(0).unwrap() {
+            "+" | "#" => self.shards.len(),
+            first_level => self.shard_index(first_level),
+        }
+    }
+
+    fn shard_index(&self, first_level: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        first_level.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+}
+
+/// A set of writes to a ShardedMqttTopicTree, dropping the batch without committing it discards
+/// the writes. The shape of a filter is checked when it is added, the quotas when the batch is
+/// committed. Removals in the same batch don't free up quota for its subscriptions.
+pub struct ShardedBatch<'a> {
+    tree: &'a ShardedMqttTopicTree,
+    /// The writes to every shard, in order
+    operations: BTreeMap<usize, Vec<TopicTreeOperations>>,
+}
+
+impl ShardedBatch<'_> {
+    pub fn add_subscription(
+        &mut self,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+        qos: QoS,
+    ) -> Result<(), SubscriptionError> {
+        self.tree.quotas.lock().limits.check_filter(&topic_filter)?;
+        let shard = self.tree.filter_shard(&topic_filter);
+        let operation = AddSubscription(topic_filter, client_id, qos);
+        self.operations.entry(shard).or_default().push(operation);
+        Ok(())
+    }
+
+    pub fn remove_subscription(&mut self, topic_filter: TopicFilter, client_id: ClientId) {
+        let shard = self.tree.filter_shard(&topic_filter);
+        let operation = RemoveSubscription(topic_filter, client_id);
+        self.operations.entry(shard).or_default().push(operation);
+    }
+
+    /// Removes the client from the shards that hold one of its subscriptions, or that this batch
+    /// writes to
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        for shard in 0..self.tree.shard_count() {
+            let written = self.operations.contains_key(&shard);
+            if written || self.tree.shard(shard).contains_client(client_id) {
+                self.operations.entry(shard).or_default().push(RemoveClient(client_id));
+            }
+        }
+    }
+
+    /// Checks the quotas and applies the writes to every shard, returning the epoch of each. The
+    /// writes are visible to readers when this returns. If the quotas reject a subscription
+    /// nothing is applied.
+    pub fn commit(self) -> Result<ShardEpochs, WriteError> {
+        // The shards are locked in order, so batches that share shards don't wait for each other
+        let mut writes = Vec::with_capacity(self.operations.len());
+        for (shard, operations) in self.operations {
+            let batch = self.tree.shard(shard).batch();
+            writes.push(ShardWrite::new(shard, batch, operations));
+        }
+        let mut quotas = self.tree.quotas.lock();
+        for index in 0..writes.len() {
+            if let Err(e) = writes[index].queue(&mut quotas) {
+                for write in writes[..=index].iter() {
+                    quotas.settle(&write.reserved, &write.before, &write.before);
+                }
+                return Err(e);
+            }
+        }
+        drop(quotas);
+        let mut epochs = Vec::with_capacity(writes.len());
+        let mut writes = writes.into_iter();
+        while let Some(write) = writes.next() {
+            let ShardWrite { shard, batch, before, reserved, .. } = write;
+            let clients = before.client_subscriptions.keys().copied();
+            match batch.commit_then(|tree| ShardCounts::read(tree, clients)) {
+                Ok((epoch, after)) => {
+                    self.tree.quotas.lock().settle(&reserved, &before, &after);
+                    epochs.push((shard, epoch));
+                }
+                Err(e) => {
+                    let mut quotas = self.tree.quotas.lock();
+                    quotas.settle(&reserved, &before, &before);
+                    for write in writes {
+                        quotas.settle(&write.reserved, &write.before, &write.before);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(ShardEpochs(epochs))
+    }
+}
+
+/// The writes of a batch to one shard, while its write lock is held
+struct ShardWrite<'a> {
+    shard: usize,
+    batch: Batch<'a>,
+    operations: Vec<TopicTreeOperations>,
+    before: ShardCounts,
+    reserved: ShardCounts,
+}
+
+impl<'a> ShardWrite<'a> {
+    fn new(shard: usize, batch: Batch<'a>, operations: Vec<TopicTreeOperations>) -> Self {
+        let clients = operations.iter().map(|x| match x {
+            AddSubscription(_, client_id, _) => *client_id,
+            RemoveSubscription(_, client_id) => *client_id,
+            RemoveClient(client_id) => *client_id,
+        });
+        let before = ShardCounts::read(&batch.tree(), clients);
+        Self {
+            shard,
+            batch,
+            operations,
+            before,
+            reserved: ShardCounts::default(),
+        }
+    }
+
+    /// Reserves the quota of every new subscription and hands the writes to the shard
+    fn queue(&mut self, quotas: &mut ShardQuotas) -> Result<(), WriteError> {
+        for operation in self.operations.drain(..) {
+            match operation {
+                AddSubscription(topic_filter, client_id, qos) => {
+                    // The shard only checks the shape, replacing an existing subscription needs
+                    // no quota
+                    let new_nodes = self.batch.tree().check_pending_subscription(
+                        &topic_filter,
+                        client_id,
+                        0,
+                        0,
+                    )?;
+                    if let Some(new_nodes) = new_nodes {
+                        quotas.reserve(client_id, new_nodes, &mut self.reserved)?;
+                    }
+                    self.batch.add_subscription(topic_filter, client_id, qos)?;
+                }
+                RemoveSubscription(topic_filter, client_id) => {
+                    self.batch.remove_subscription(topic_filter, client_id);
+                }
+                RemoveClient(client_id) => self.batch.remove_client(client_id),
+            }
+        }
+        Ok(())
+    }
+}
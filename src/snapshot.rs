@@ -0,0 +1,358 @@
+//! A compact binary snapshot of a TopicTree.
+//!
+//! The layout is a header, a table with every distinct level and group name, the nodes of the tree
+//! in depth first order and a trailing CRC-32 of everything before it. Nodes refer to strings by
+//! their index in the table, so names that repeat across the tree are only stored once, and nodes
+//! are rebuilt directly without parsing any topic filters.
+//!
+//! ```text
+//! snapshot     = magic:[u8; 4] version:u16 strings:u32 string* node crc:u32
+//! string       = len:u32 bytes
+//! node         = subscriptions flags:u8 [subscriptions if flags & 1] [node if flags & 2]
+//!                children:u32 (name:u32 node)*
+//! subscriptions = clients:u32 (client_id:u64 qos:u8)* groups:u32 (name:u32 members:u32 (client_id:u64 qos:u8)*)*
+//! ```
+//!
+//! All integers are little endian. Reading checks that every level and group name is valid in a
+//! topic filter, since a snapshot with a correct checksum can still have been crafted.
+
+use crate::intern::Interner;
+use crate::topic::is_literal_level;
+use crate::topic_tree::{ClientGroup, Shared, SubscriptionInfo, TopicNode};
+use crate::{ClientId, QoS, Subscriber, TopicTree};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 4] = *b"MTTS";
+const VERSION: u16 = 1;
+const HAS_MULTI_LEVEL_WILDCARD: u8 = 1;
+const HAS_SINGLE_LEVEL_WILDCARD: u8 = 2;
+/// The deepest tree a snapshot holds, nodes are encoded and decoded recursively
+const MAX_DEPTH: usize = 256;
+/// The length of `$share/` and the `/` after the group
+const SHARE_PREFIX_LEN: usize = 8;
+
+/// The reason a snapshot could not be read
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Corrupt,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "could not read snapshot: {e}"),
+            SnapshotError::InvalidMagic => write!(f, "data is not a topic tree snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "snapshot version {version} is not supported")
+            }
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum does not match"),
+            SnapshotError::Corrupt => write!(f, "snapshot is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(value: io::Error) -> Self {
+        SnapshotError::Io(value)
+    }
+}
+
+impl TopicTree {
+    /// Writes the whole tree to the writer, the limits of the tree are not part of the snapshot.
+    /// Fails with [`io::ErrorKind::InvalidInput`] without writing anything if the tree holds a
+    /// filter of more than 256 levels, since such a snapshot could not be read back.
+    pub fn write_snapshot<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let symbols = &self.interner;
+        let mut encoder = Encoder::default();
+        encoder.node(symbols, &self.root_node, 0)?;
+        let mut buf = Vec::with_capacity(encoder.nodes.len() + 64 * encoder.strings.len());
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&(encoder.strings.len() as u32).to_le_bytes());
+        for string in encoder.strings.iter() {
+            buf.extend_from_slice(&(string.len() as u32).to_le_bytes());
+            buf.extend_from_slice(string.as_bytes());
+        }
+        buf.extend_from_slice(&encoder.nodes);
+        let checksum = crc32(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+
+    /// Rebuilds a tree from a snapshot, the tree gets the default limits
+    pub fn read_snapshot<R: Read>(mut reader: R) -> Result<TopicTree, SnapshotError> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        if buf.len() < MAGIC.len() + 2 + 4 || buf[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        };
+        let (data, checksum) = buf.split_at(buf.len() - 4);
+        let mut decoder = Decoder {
+            data,
+            pos: MAGIC.len(),
+            strings: Vec::new(),
+            topic_tree: TopicTree::default(),
+        };
+        let version = u16::from_le_bytes(decoder.bytes::<2>()?);
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        };
+        if crc32(data) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(SnapshotError::ChecksumMismatch);
+        };
+        let string_count = decoder.u32()?;
+        for _ in 0..string_count {
+            let len = decoder.u32()? as usize;
+            let string = std::str::from_utf8(decoder.slice(len)?)
+                .map_err(|_| SnapshotError::Corrupt)?;
+            decoder.strings.push(string.to_owned());
+        }
+        let root_node = decoder.node(0, 0)?;
+        if decoder.pos != data.len() {
+            return Err(SnapshotError::Corrupt);
+        };
+        let mut topic_tree = decoder.topic_tree;
+        topic_tree.root_node = root_node;
+        Ok(topic_tree)
+    }
+}
+
+#[derive(Default)]
+struct Encoder<'a> {
+    string_ids: HashMap<&'a str, u32>,
+    strings: Vec<&'a str>,
+    nodes: Vec<u8>,
+}
+
+impl<'a> Encoder<'a> {
+    fn string(&mut self, string: &'a str) {
+        let next_id = self.strings.len() as u32;
+        let id = *self.string_ids.entry(string).or_insert(next_id);
+        if id == next_id {
+            self.strings.push(string);
+        }
+        self.u32(id);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.nodes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn subscriber(&mut self, client_id: ClientId, qos: QoS) {
+        self.nodes.extend_from_slice(&client_id.to_le_bytes());
+        self.nodes.push(qos as u8);
+    }
+
+    fn subscriptions(&mut self, sub_info: &'a SubscriptionInfo) {
+        self.u32(sub_info.client_subscriptions.len() as u32);
+        for (client_id, qos) in sub_info.client_subscriptions.iter() {
+            self.subscriber(*client_id, *qos);
+        }
+        self.u32(sub_info.shared_subscriptions.len() as u32);
+        for group in sub_info.shared_subscriptions.iter() {
+            self.string(&group.group_id);
+            self.u32(group.clients.len() as u32);
+            for subscriber in group.clients.iter() {
+                self.subscriber(subscriber.client_id, subscriber.qos);
+            }
+        }
+    }
+
+    fn node(&mut self, symbols: &'a Interner, node: &'a TopicNode, depth: usize) -> io::Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("topic tree is deeper than the {MAX_DEPTH} levels a snapshot can hold"),
+            ));
+        }
+        self.subscriptions(&node.content);
+        let mut flags = 0;
+        if node.multi_level_wildcard.is_some() {
+            flags |= HAS_MULTI_LEVEL_WILDCARD;
+        }
+        if node.single_level_wildcard.is_some() {
+            flags |= HAS_SINGLE_LEVEL_WILDCARD;
+        }
+        self.nodes.push(flags);
+        if let Some(routeinfo) = node.multi_level_wildcard.as_deref() {
+            self.subscriptions(routeinfo);
+        }
+        if let Some(single_wildcard_match) = node.single_level_wildcard.as_deref() {
+            self.node(symbols, single_wildcard_match, depth + 1)?;
+        }
+        self.u32(node.sub_nodes.len() as u32);
+        for (topic_level, sub_node) in node.sub_nodes.iter() {
+            self.string(symbols.name(topic_level));
+            self.node(symbols, sub_node, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the nodes back, and rebuilds the counters of the TopicTree while doing so
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    strings: Vec<String>,
+    topic_tree: TopicTree,
+}
+
+impl<'a> Decoder<'a> {
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.pos.checked_add(len).ok_or(SnapshotError::Corrupt)?;
+        let slice = self.data.get(self.pos..end).ok_or(SnapshotError::Corrupt)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.bytes::<4>()?))
+    }
+
+    fn string(&mut self) -> Result<String, SnapshotError> {
+        let id = self.u32()? as usize;
+        self.strings.get(id).cloned().ok_or(SnapshotError::Corrupt)
+    }
+
+    /// A level or group name, which has to be valid in a topic filter
+    fn level(&mut self) -> Result<String, SnapshotError> {
+        let level = self.string()?;
+        if !is_literal_level(&level) {
+            return Err(SnapshotError::Corrupt);
+        }
+        Ok(level)
+    }
+
+    fn subscriber(&mut self) -> Result<Subscriber, SnapshotError> {
+        let client_id = u64::from_le_bytes(self.bytes::<8>()?);
+        let qos = QoS::try_from(self.bytes::<1>()?[0]).map_err(|_| SnapshotError::Corrupt)?;
+        self.topic_tree.subscribers += 1;
+        *self.topic_tree.client_subscriptions.entry(client_id).or_insert(0) += 1;
+        Ok(Subscriber { client_id, qos })
+    }
+
+    /// The subscriptions of a filter of `filter_len` bytes
+    fn subscriptions(&mut self, filter_len: usize) -> Result<SubscriptionInfo, SnapshotError> {
+        let mut sub_info = SubscriptionInfo::default();
+        for _ in 0..self.u32()? {
+            let subscriber = self.subscriber()?;
+            if filter_len == 0
+                || filter_len > u16::MAX as usize
+                || !sub_info.add_client_subscription(subscriber.client_id, subscriber.qos)
+            {
+                return Err(SnapshotError::Corrupt);
+            }
+        }
+        for _ in 0..self.u32()? {
+            let group_id = self.level()?;
+            let shared_len = SHARE_PREFIX_LEN + group_id.len() + filter_len;
+            if filter_len == 0
+                || group_id.is_empty()
+                || shared_len > u16::MAX as usize
+                || sub_info.shared_subscriptions.iter().any(|x| x.group_id == group_id)
+            {
+                return Err(SnapshotError::Corrupt);
+            }
+            let mut clients: Vec<Subscriber> = Vec::new();
+            for _ in 0..self.u32()? {
+                let subscriber = self.subscriber()?;
+                if clients.iter().any(|x| x.client_id == subscriber.client_id) {
+                    return Err(SnapshotError::Corrupt);
+                }
+                clients.push(subscriber);
+            }
+            if clients.is_empty() {
+                return Err(SnapshotError::Corrupt);
+            }
+            sub_info.shared_subscriptions.push(ClientGroup { group_id, clients });
+        }
+        Ok(sub_info)
+    }
+
+    /// A node `depth` levels below the root, whose filter is `filter_len` bytes long
+    #[cfg_attr(not(feature = "rcu"), allow(clippy::useless_conversion))]
+    fn node(&mut self, depth: usize, filter_len: usize) -> Result<TopicNode, SnapshotError> {
+        if depth > MAX_DEPTH {
+            return Err(SnapshotError::Corrupt);
+        }
+        // The length of the filter of a sub node with a level of `len` bytes
+        let sub_filter_len = |len: usize| if depth == 0 { len } else { filter_len + 1 + len };
+        let mut node = TopicNode {
+            content: self.subscriptions(filter_len)?.into(),
+            ..Default::default()
+        };
+        let flags = self.bytes::<1>()?[0];
+        if flags & !(HAS_MULTI_LEVEL_WILDCARD | HAS_SINGLE_LEVEL_WILDCARD) != 0 {
+            return Err(SnapshotError::Corrupt);
+        }
+        if flags & HAS_MULTI_LEVEL_WILDCARD != 0 {
+            node.multi_level_wildcard = Some(Shared::new(self.subscriptions(sub_filter_len(1))?));
+            self.topic_tree.nodes += 1;
+        }
+        if flags & HAS_SINGLE_LEVEL_WILDCARD != 0 {
+            node.single_level_wildcard = Some(Shared::new(self.node(depth + 1, sub_filter_len(1))?));
+            self.topic_tree.nodes += 1;
+        }
+        for _ in 0..self.u32()? {
+            let topic_level = self.level()?;
+            // `$share` as the first level would make the filter a shared subscription
+            let taken = self
+                .topic_tree
+                .interner
+                .get(&topic_level)
+                .is_some_and(|x| node.sub_nodes.contains_key(x));
+            if taken || (depth == 0 && topic_level == "$share") {
+                return Err(SnapshotError::Corrupt);
+            }
+            let sub_node = self.node(depth + 1, sub_filter_len(topic_level.len()))?;
+            let topic_level = self.topic_tree.interner.intern(topic_level);
+            node.sub_nodes.insert(topic_level, sub_node);
+            self.topic_tree.nodes += 1;
+        }
+        Ok(node)
+    }
+}
+
+/// CRC-32 as used by zlib and png
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    let mut crc = !0u32;
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
@@ -0,0 +1,174 @@
+use crate::intern::{Interner, Level, level_memory};
+use crate::topic_tree::{ClientGroup, Slots, SubscriptionInfo, TopicNode};
+use crate::{ClientId, QoS, Subscriber, TopicTree};
+use std::fmt::Write;
+use std::mem::size_of;
+
+/// Counters that describe the shape of a TopicTree
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeStats {
+    /// The number of nodes below the root, including wildcard nodes
+    pub nodes: usize,
+    /// The number of nodes per level, the first entry holds the nodes of the first level
+    pub depth_histogram: Vec<usize>,
+    pub single_level_wildcard_nodes: usize,
+    pub multi_level_wildcard_nodes: usize,
+    /// All subscriptions, shared subscriptions are counted once per group member
+    pub subscriptions: usize,
+    pub client_subscriptions: usize,
+    pub shared_subscriptions: usize,
+    pub shared_groups: usize,
+    /// The number of distinct clients with at least one subscription
+    pub clients: usize,
+    /// An estimate of the heap and inline memory used by the tree, in bytes
+    pub estimated_memory: usize,
+}
+
+impl TreeStats {
+    /// Adds the counters of another tree, except for the clients which may be shared
+    pub(crate) fn add(&mut self, other: &TreeStats) {
+        self.nodes += other.nodes;
+        if self.depth_histogram.len() < other.depth_histogram.len() {
+            self.depth_histogram.resize(other.depth_histogram.len(), 0);
+        }
+        for (count, other) in self.depth_histogram.iter_mut().zip(other.depth_histogram.iter()) {
+            *count += other;
+        }
+        self.single_level_wildcard_nodes += other.single_level_wildcard_nodes;
+        self.multi_level_wildcard_nodes += other.multi_level_wildcard_nodes;
+        self.subscriptions += other.subscriptions;
+        self.client_subscriptions += other.client_subscriptions;
+        self.shared_subscriptions += other.shared_subscriptions;
+        self.shared_groups += other.shared_groups;
+        self.estimated_memory += other.estimated_memory;
+    }
+}
+
+impl TopicTree {
+    /// Walks the whole tree to collect its statistics
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            clients: self.client_subscriptions.len(),
+            estimated_memory: size_of::<TopicTree>()
+                + self.client_subscriptions.slots() * (size_of::<(ClientId, usize)>() + 1)
+                + self.interner.estimated_memory(),
+            ..Default::default()
+        };
+        node_stats(&self.root_node, 0, &mut stats);
+        stats
+    }
+
+    /// Prints the tree with one node per line, indented by its level. Subscriptions are listed
+    /// after the level as `client@qos`, and shared groups as `$share/<group>[client@qos ...]`.
+    /// Levels are sorted, so the output of two equal trees is the same.
+    ///
+    /// ```text
+    /// home
+    ///   # 1@0
+    ///   +
+    ///     light 2@1 $share/g[3@0 4@2]
+    /// ```
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        dump_node(&self.interner, &self.root_node, 0, &mut out);
+        out
+    }
+}
+
+fn node_stats(node: &TopicNode, depth: usize, stats: &mut TreeStats) {
+    subscription_stats(&node.content, stats);
+    stats.estimated_memory += node.sub_nodes.slots()
+        * (size_of::<Level>() + size_of::<TopicNode>() + 1);
+    if let Some(routeinfo) = node.multi_level_wildcard.as_deref() {
+        count_node(depth, stats);
+        stats.multi_level_wildcard_nodes += 1;
+        stats.estimated_memory += size_of::<SubscriptionInfo>();
+        subscription_stats(routeinfo, stats);
+    }
+    if let Some(single_wildcard_match) = node.single_level_wildcard.as_deref() {
+        count_node(depth, stats);
+        stats.single_level_wildcard_nodes += 1;
+        stats.estimated_memory += size_of::<TopicNode>();
+        node_stats(single_wildcard_match, depth + 1, stats);
+    }
+    for (topic_level, sub_node) in node.sub_nodes.iter() {
+        count_node(depth, stats);
+        stats.estimated_memory += level_memory(topic_level);
+        node_stats(sub_node, depth + 1, stats);
+    }
+}
+
+fn count_node(depth: usize, stats: &mut TreeStats) {
+    stats.nodes += 1;
+    if stats.depth_histogram.len() <= depth {
+        stats.depth_histogram.resize(depth + 1, 0);
+    }
+    stats.depth_histogram[depth] += 1;
+}
+
+fn subscription_stats(sub_info: &SubscriptionInfo, stats: &mut TreeStats) {
+    stats.client_subscriptions += sub_info.client_subscriptions.len();
+    stats.subscriptions += sub_info.client_subscriptions.len();
+    stats.shared_groups += sub_info.shared_subscriptions.len();
+    stats.estimated_memory += sub_info.client_subscriptions.capacity()
+        * (size_of::<(ClientId, QoS)>() + 1)
+        + sub_info.shared_subscriptions.capacity() * size_of::<ClientGroup>();
+    for group in sub_info.shared_subscriptions.iter() {
+        stats.shared_subscriptions += group.clients.len();
+        stats.subscriptions += group.clients.len();
+        stats.estimated_memory +=
+            group.group_id.capacity() + group.clients.capacity() * size_of::<Subscriber>();
+    }
+}
+
+fn dump_node(symbols: &Interner, node: &TopicNode, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    if let Some(routeinfo) = node.multi_level_wildcard.as_deref() {
+        out.push_str(&indent);
+        out.push('#');
+        dump_subscriptions(routeinfo, out);
+        out.push('\n');
+    }
+    if let Some(single_wildcard_match) = node.single_level_wildcard.as_deref() {
+        out.push_str(&indent);
+        out.push('+');
+        dump_subscriptions(&single_wildcard_match.content, out);
+        out.push('\n');
+        dump_node(symbols, single_wildcard_match, depth + 1, out);
+    }
+    let mut sub_nodes: Vec<(&str, &TopicNode)> = node
+        .sub_nodes
+        .iter()
+        .map(|(topic_level, sub_node)| (symbols.name(topic_level), sub_node))
+        .collect();
+    sub_nodes.sort_by_key(|x| x.0);
+    for (topic_level, sub_node) in sub_nodes {
+        out.push_str(&indent);
+        out.push_str(topic_level);
+        dump_subscriptions(&sub_node.content, out);
+        out.push('\n');
+        dump_node(symbols, sub_node, depth + 1, out);
+    }
+}
+
+fn dump_subscriptions(sub_info: &SubscriptionInfo, out: &mut String) {
+    let mut clients: Vec<(&ClientId, &QoS)> = sub_info.client_subscriptions.iter().collect();
+    clients.sort();
+    for (client_id, qos) in clients {
+        let _ = write!(out, " {client_id}@{}", *qos as u8);
+    }
+    let mut groups: Vec<&ClientGroup> = sub_info.shared_subscriptions.iter().collect();
+    groups.sort_by_key(|x| &x.group_id);
+    for group in groups {
+        let _ = write!(out, " $share/{}[", group.group_id);
+        let mut clients = group.clients.clone();
+        clients.sort_by_key(|x| x.client_id);
+        for (i, subscriber) in clients.iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            let _ = write!(out, "{}@{}", subscriber.client_id, subscriber.qos as u8);
+        }
+        out.push(']');
+    }
+}
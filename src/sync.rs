@@ -1,24 +1,64 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc};
-use left_right::{Absorb, ReadHandle, ReadHandleFactory, WriteHandle};
-use parking_lot::Mutex;
-use crate::sync::TopicTreeOperations::{AddSubscription, RemoveSubscription};
-use crate::{ClientId, QoS, Subscriber, TopicFilter, TopicName, TopicTree};
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+use left_right::{Absorb, ReadHandle, WriteHandle};
+use parking_lot::{Mutex, MutexGuard};
+use crate::events::SubscriptionEvent;
+#[cfg(feature = "metrics")]
+use crate::metrics::{MatchCounts, MetricsRecorder};
+use crate::snapshot::SnapshotError;
+use crate::topic_tree::{MatchVisitor, deduplicated, with_qos};
+use crate::sync::TopicIndexOperations::{InsertTopic, RemoveTopic};
+use crate::sync::TopicTreeOperations::{AddSubscription, RemoveClient, RemoveSubscription};
+use crate::wal::{RecoveryError, WriteAheadLog};
+use crate::{
+    ClientId, QoS, Subscriber, SubscriptionError, TopicFilter, TopicIndex, TopicLimits, TopicName,
+    TopicTree, TreeStats,
+};
 
 pub enum  TopicTreeOperations {
     AddSubscription(TopicFilter, ClientId, QoS),
     RemoveSubscription(TopicFilter, ClientId),
+    RemoveClient(ClientId),
 }
 
-impl Absorb<TopicTreeOperations> for TopicTree {
-    fn absorb_first(&mut self, operation: &mut TopicTreeOperations, _: &Self) {
-        match operation {
+impl TopicTreeOperations {
+    pub(crate) fn apply_to(&self, topic_tree: &mut TopicTree) -> Result<(), SubscriptionError> {
+        match self {
             AddSubscription(topic_filter, client_id, qos) => {
-                self.add_subscription(topic_filter.clone(), client_id.clone(), qos.clone())
+                topic_tree.add_subscription(topic_filter.clone(), *client_id, *qos)?;
             }
             RemoveSubscription(topic_filer, client_id) => {
-                self.remove_subscription(topic_filer.clone(), client_id.clone())
+                topic_tree.remove_subscription(topic_filer.clone(), *client_id);
+            }
+            RemoveClient(client_id) => {
+                topic_tree.remove_client(*client_id);
             }
         }
+        Ok(())
+    }
+}
+
+/// Operations are checked against the limits before they are appended, counting the operations
+/// that were not published yet, so they apply cleanly to both copies
+pub(crate) const CHECKED: &str = "operation was checked against the limits before it was appended";
+
+#[cfg(not(feature = "rcu"))]
+impl Absorb<TopicTreeOperations> for TopicTree {
+    fn absorb_first(&mut self, operation: &mut TopicTreeOperations, _: &Self) {
+        operation.apply_to(self).expect(CHECKED);
+    }
+
+    /// The events of the operation were already emitted when it was applied to the first copy
+    fn absorb_second(&mut self, operation: TopicTreeOperations, _: &Self) {
+        let listener = self.listener.take();
+        operation.apply_to(self).expect(CHECKED);
+        self.listener = listener;
     }
 
     fn sync_with(&mut self, first: &Self) {
@@ -26,9 +66,109 @@ impl Absorb<TopicTreeOperations> for TopicTree {
     }
 }
 
+/// The reason a write to a MqttTopicTree failed, nothing was applied in either case
+#[derive(Debug)]
+pub enum WriteError {
+    /// The subscription exceeds the limits of the tree
+    Rejected(SubscriptionError),
+    /// The operations could not be written to the write-ahead log
+    Log(io::Error),
+    /// The writer thread of an async tree has stopped
+    #[cfg(feature = "tokio")]
+    Closed,
+}
+
+impl WriteError {
+    /// The MQTT 5 reason code to send back to the client, a failing log is an unspecified error
+    pub fn reason_code(&self) -> u8 {
+        match self {
+            WriteError::Rejected(e) => e.reason_code(),
+            WriteError::Log(_) => 0x80,
+            #[cfg(feature = "tokio")]
+            WriteError::Closed => 0x80,
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Rejected(e) => write!(f, "subscription rejected: {e}"),
+            WriteError::Log(e) => write!(f, "could not write to the write-ahead log: {e}"),
+            #[cfg(feature = "tokio")]
+            WriteError::Closed => write!(f, "the writer of the topic tree has stopped"),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WriteError::Rejected(e) => Some(e),
+            WriteError::Log(e) => Some(e),
+            #[cfg(feature = "tokio")]
+            WriteError::Closed => None,
+        }
+    }
+}
+
+impl From<SubscriptionError> for WriteError {
+    fn from(value: SubscriptionError) -> Self {
+        WriteError::Rejected(value)
+    }
+}
+
+/// Identifies a committed write, the epochs of a tree increase with every commit. See
+/// [`MqttTopicTree::wait_visible`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Epoch(pub(crate) u64);
+
+/// The copies of a TopicTree are kept by left-right, or as versions that share their nodes with
+/// the `rcu` feature
+#[cfg(not(feature = "rcu"))]
+type TreeWriteHandle = WriteHandle<TopicTree, TopicTreeOperations>;
+#[cfg(not(feature = "rcu"))]
+type TreeReadHandle = ReadHandle<TopicTree>;
+#[cfg(not(feature = "rcu"))]
+type TreeReadHandleFactory = left_right::ReadHandleFactory<TopicTree>;
+#[cfg(not(feature = "rcu"))]
+type TreeReadGuard<'a> = left_right::ReadGuard<'a, TopicTree>;
+#[cfg(feature = "rcu")]
+type TreeWriteHandle = crate::rcu::WriteHandle;
+#[cfg(feature = "rcu")]
+type TreeReadHandle = crate::rcu::ReadHandle;
+#[cfg(feature = "rcu")]
+type TreeReadHandleFactory = crate::rcu::ReadHandleFactory;
+#[cfg(feature = "rcu")]
+type TreeReadGuard<'a> = crate::rcu::ReadGuard<'a>;
+
+/// The write side of a MqttTopicTree, only durable trees have a log
+pub(crate) struct TopicTreeWriter {
+    write_handle: TreeWriteHandle,
+    wal: Option<WriteAheadLog>,
+    /// The epoch of the last commit
+    epoch: u64,
+    /// The epoch of the last commit that was published, shared with the read side
+    published: Arc<AtomicU64>,
+    /// The subscriptions and nodes that deferred commits may add once they are published, new
+    /// subscriptions are checked against the published copy plus these
+    unpublished_subscriptions: HashMap<ClientId, usize>,
+    unpublished_nodes: usize,
+}
+
+impl TopicTreeWriter {
+    fn publish(&mut self) {
+        self.write_handle.publish();
+        self.published.store(self.epoch, Ordering::Release);
+        self.unpublished_subscriptions.clear();
+        self.unpublished_nodes = 0;
+    }
+}
+
 pub struct MqttTopicTreeCreator {
-    write_handle: Arc<Mutex<WriteHandle<TopicTree, TopicTreeOperations>>>,
-    factory: ReadHandleFactory<TopicTree>
+    writer: Arc<Mutex<TopicTreeWriter>>,
+    published: Arc<AtomicU64>,
+    factory: TreeReadHandleFactory
 }
 
 impl MqttTopicTreeCreator {
@@ -36,55 +176,483 @@ impl MqttTopicTreeCreator {
         let read_handle = self.factory.handle();
         MqttTopicTree {
             read_handle,
-            write_handle: self.write_handle
+            writer: self.writer,
+            published: self.published,
+            #[cfg(feature = "metrics")]
+            recorder: None,
         }
     }
 }
 
-impl Default for MqttTopicTreeCreator {
-    fn default() -> Self {
-        let (write, read) = left_right::new::<TopicTree, TopicTreeOperations>();
+impl MqttTopicTreeCreator {
+    pub fn with_limits(limits: TopicLimits) -> Self {
+        Self::from_topic_tree(TopicTree::with_limits(limits))
+    }
+
+    /// Starts from an existing tree, e.g. one restored from a snapshot
+    pub fn from_topic_tree(topic_tree: TopicTree) -> Self {
+        Self::from_parts(topic_tree, None)
+    }
+
+    /// Recovers a durable tree from the snapshot and write-ahead log in the directory, see
+    /// [`MqttTopicTree::recover`]
+    pub fn recover<P: AsRef<Path>>(dir: P, limits: TopicLimits) -> Result<Self, RecoveryError> {
+        let (topic_tree, wal) = WriteAheadLog::recover(dir.as_ref(), limits)?;
+        Ok(Self::from_parts(topic_tree, Some(wal)))
+    }
+
+    fn from_parts(topic_tree: TopicTree, wal: Option<WriteAheadLog>) -> Self {
+        #[cfg(not(feature = "rcu"))]
+        let (mut write, _read) =
+            left_right::new_from_empty::<TopicTree, TopicTreeOperations>(topic_tree);
+        // Before the first publish operations are applied with absorb_second, which would not
+        // emit any events
+        #[cfg(not(feature = "rcu"))]
+        write.publish();
+        #[cfg(feature = "rcu")]
+        let write = TreeWriteHandle::new(topic_tree);
         let factory = write.factory();
+        let published = Arc::new(AtomicU64::new(0));
+        let writer = TopicTreeWriter {
+            write_handle: write,
+            wal,
+            epoch: 0,
+            published: published.clone(),
+            unpublished_subscriptions: HashMap::new(),
+            unpublished_nodes: 0,
+        };
         Self {
-            write_handle: Arc::new(Mutex::new(write)),
+            writer: Arc::new(Mutex::new(writer)),
+            published,
             factory
         }
     }
 }
 
+impl Default for MqttTopicTreeCreator {
+    fn default() -> Self {
+        Self::from_topic_tree(TopicTree::default())
+    }
+}
+
 #[derive(Clone)]
 pub struct MqttTopicTree {
-    read_handle: ReadHandle<TopicTree>,
-    write_handle: Arc<Mutex<WriteHandle<TopicTree, TopicTreeOperations>>>
+    read_handle: TreeReadHandle,
+    writer: Arc<Mutex<TopicTreeWriter>>,
+    published: Arc<AtomicU64>,
+    #[cfg(feature = "metrics")]
+    recorder: Option<Arc<dyn MetricsRecorder>>,
+}
+
+impl Default for MqttTopicTree {
+    fn default() -> Self {
+        MqttTopicTreeCreator::default().to_mqtt_topic_tree()
+    }
 }
 
 impl MqttTopicTree {
+    pub fn with_limits(limits: TopicLimits) -> Self {
+        MqttTopicTreeCreator::with_limits(limits).to_mqtt_topic_tree()
+    }
+
+    pub fn from_topic_tree(topic_tree: TopicTree) -> Self {
+        MqttTopicTreeCreator::from_topic_tree(topic_tree).to_mqtt_topic_tree()
+    }
+
+    /// Creates a tree that calls the listener for every subscription change, see
+    /// [`TopicTree::set_listener`]. The listener is called while the writer holds the write lock.
+    pub fn with_listener<F>(limits: TopicLimits, listener: F) -> Self
+    where
+        F: Fn(&SubscriptionEvent) + Send + Sync + 'static,
+    {
+        let mut topic_tree = TopicTree::with_limits(limits);
+        topic_tree.set_listener(listener);
+        Self::from_topic_tree(topic_tree)
+    }
+
+    /// Reports lookups and writes to the recorder, clones made afterwards share it. Lookups count
+    /// the matches by kind while they walk the tree, which tracks the path to every match and is
+    /// a little slower than a lookup without a recorder.
+    #[cfg(feature = "metrics")]
+    pub fn with_recorder(mut self, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Starts from a snapshot written by [`TopicTree::write_snapshot`] or
+    /// [`MqttTopicTree::write_snapshot`], with the default limits
+    pub fn from_snapshot<R: Read>(reader: R) -> Result<Self, SnapshotError> {
+        Ok(Self::from_topic_tree(TopicTree::read_snapshot(reader)?))
+    }
+
+    /// Writes a snapshot of the current state, writers are not blocked while it is written
+    pub fn write_snapshot<W: Write>(&self, writer: W) -> io::Result<()> {
+        let a = self.read_handle.enter().unwrap();
+        a.write_snapshot(writer)
+    }
+
+    /// Opens a durable tree in the directory. The last snapshot is loaded and the write-ahead log
+    /// is replayed on top of it, after which every write is logged before it is applied. A torn
+    /// final record or an unfinished batch at the end of the log is truncated.
+    pub fn recover<P: AsRef<Path>>(dir: P, limits: TopicLimits) -> Result<Self, RecoveryError> {
+        Ok(MqttTopicTreeCreator::recover(dir, limits)?.to_mqtt_topic_tree())
+    }
+
+    /// Replaces the snapshot of a durable tree with the current state and empties its log.
+    /// Writers are blocked while the snapshot is written. If the snapshot can't be written, as
+    /// described in [`TopicTree::write_snapshot`], the old snapshot and the log are kept.
+    pub fn checkpoint(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock();
+        let writer = &mut *writer;
+        if writer.wal.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "topic tree has no write-ahead log",
+            ));
+        };
+        // Deferred commits are logged already, so they have to be in the snapshot as well
+        writer.publish();
+        let wal = writer.wal.as_mut().unwrap();
+        let topic_tree = writer.write_handle.enter().unwrap();
+        wal.checkpoint(&topic_tree)
+    }
+
+    /// Starts a batch of writes that are logged and published together when it is committed.
+    /// Other writers are blocked until the batch is committed or dropped.
+    pub fn batch(&self) -> Batch<'_> {
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let writer = self.writer.lock();
+        #[cfg(feature = "metrics")]
+        if let Some(recorder) = self.recorder.as_deref() {
+            recorder.record_lock_wait(start.elapsed());
+        }
+        Batch {
+            writer,
+            operations: Vec::new(),
+            pending_subscriptions: HashMap::new(),
+            pending_nodes: 0,
+            #[cfg(feature = "metrics")]
+            recorder: self.recorder.clone(),
+        }
+    }
 
     pub fn add_subscription(
         &self,
         topic_filter: TopicFilter,
         client_id: ClientId,
         qos: QoS,
-    ) {
-        let mut write_handle = self.write_handle.lock();
-        let operation = AddSubscription(topic_filter, client_id, qos);
-        write_handle.append(operation);
-        write_handle.publish();
+    ) -> Result<Epoch, WriteError> {
+        let mut batch = self.batch();
+        batch.add_subscription(topic_filter, client_id, qos)?;
+        batch.commit()
     }
 
     pub fn remove_subscription(
         &self,
         topic_filter: TopicFilter,
         client_id: ClientId,
-    ) {
+    ) -> Result<Epoch, WriteError> {
+        let mut batch = self.batch();
+        batch.remove_subscription(topic_filter, client_id);
+        batch.commit()
+    }
+
+    /// Removes all subscriptions of a client, e.g. when its session ends
+    pub fn remove_client(&self, client_id: ClientId) -> Result<Epoch, WriteError> {
+        let mut batch = self.batch();
+        batch.remove_client(client_id);
+        batch.commit()
+    }
+
+    /// The epoch of the last write that readers can see
+    pub fn visible_epoch(&self) -> Epoch {
+        Epoch(self.published.load(Ordering::Acquire))
+    }
+
+    pub fn is_visible(&self, epoch: Epoch) -> bool {
+        self.visible_epoch() >= epoch
+    }
+
+    /// Makes sure the write with the epoch is visible to every reader of the tree. Writes that were
+    /// committed with [`Batch::commit_deferred`] are published if necessary, which blocks on the
+    /// write lock. Writes committed with [`Batch::commit`] are visible as soon as it returns.
+    pub fn wait_visible(&self, epoch: Epoch) {
+        if self.is_visible(epoch) {
+            return;
+        }
+        let mut writer = self.writer.lock();
+        if !self.is_visible(epoch) {
+            writer.publish();
+        }
+    }
+
+    /// Publishes the writes that were committed with [`Batch::commit_deferred`]
+    pub fn publish(&self) {
+        self.writer.lock().publish();
+    }
+
+    pub fn get_subscriptions(&self, publish_topic: &TopicName) -> Vec<Subscriber> {
+        self.lookup(|a, visit| a.find_subscriptions(publish_topic, visit))
+    }
+
+    /// Runs a lookup on the published tree, and reports it to the recorder if one is set. The
+    /// lookup is given a visitor that counts the matches by kind while it walks the tree.
+    #[inline]
+    fn lookup<F>(&self, f: F) -> Vec<Subscriber>
+    where
+        F: FnOnce(&TopicTree, Option<MatchVisitor>) -> Vec<Subscriber>,
+    {
+        let a = self.read_handle.enter().unwrap();
+        #[cfg(feature = "metrics")]
+        if let Some(recorder) = self.recorder.as_deref() {
+            let mut matches = MatchCounts::default();
+            let start = Instant::now();
+            let results = f(&a, Some(&mut |levels, sub_info| matches.count(levels, sub_info)));
+            recorder.record_lookup(start.elapsed(), results.len());
+            recorder.record_matches(&matches);
+            return results;
+        }
+        f(&a, None)
+    }
+
+    /// The published tree
+    #[cfg(all(test, feature = "rcu"))]
+    pub(crate) fn published_tree(&self) -> TreeReadGuard<'_> {
+        self.read_handle.enter().unwrap()
+    }
+
+    /// Whether the published tree holds any subscription of the client
+    pub(crate) fn contains_client(&self, client_id: ClientId) -> bool {
+        let a = self.read_handle.enter().unwrap();
+        a.client_subscriptions.contains_key(&client_id)
+    }
+
+    /// See [`TopicTree::iter`], the subscriptions are collected before they are returned
+    pub fn iter(&self) -> impl Iterator<Item = (TopicFilter, ClientId, QoS, Option<String>)> {
+        let a = self.read_handle.enter().unwrap();
+        a.iter().collect::<Vec<_>>().into_iter()
+    }
+
+    /// See [`TopicTree::iter_under`], the subscriptions are collected before they are returned
+    pub fn iter_under(
+        &self,
+        prefix: &str,
+    ) -> impl Iterator<Item = (TopicFilter, ClientId, QoS, Option<String>)> {
+        let a = self.read_handle.enter().unwrap();
+        a.iter_under(prefix).collect::<Vec<_>>().into_iter()
+    }
+
+    /// Collects the statistics of the published tree, writers are not blocked while doing so
+    pub fn stats(&self) -> TreeStats {
+        let a = self.read_handle.enter().unwrap();
+        a.stats()
+    }
+
+    /// See [`TopicTree::dump`]
+    pub fn dump(&self) -> String {
+        let a = self.read_handle.enter().unwrap();
+        a.dump()
+    }
+
+    /// See [`TopicTree::get_subscriptions_with_qos`]
+    pub fn get_subscriptions_with_qos(
+        &self,
+        publish_topic: &TopicName,
+        publish_qos: QoS,
+    ) -> Vec<Subscriber> {
+        self.lookup(|a, visit| with_qos(a.find_subscriptions(publish_topic, visit), publish_qos))
+    }
+
+    /// See [`TopicTree::get_subscriptions_deduplicated`]
+    pub fn get_subscriptions_deduplicated(&self, publish_topic: &TopicName) -> Vec<Subscriber> {
+        self.lookup(|a, visit| deduplicated(a.find_subscriptions(publish_topic, visit)))
+    }
+
+    /// See [`TopicTree::get_subscriptions_deduplicated_with_qos`]
+    pub fn get_subscriptions_deduplicated_with_qos(
+        &self,
+        publish_topic: &TopicName,
+        publish_qos: QoS,
+    ) -> Vec<Subscriber> {
+        self.lookup(|a, visit| {
+            with_qos(deduplicated(a.find_subscriptions(publish_topic, visit)), publish_qos)
+        })
+    }
+
+    /// See [`TopicTree::get_routed_subscriptions`]
+    pub fn get_routed_subscriptions(
+        &self,
+        publish_topic: &TopicName,
+        shared_filters: &[TopicFilter],
+    ) -> Vec<Subscriber> {
+        self.lookup(|a, visit| a.find_routed_subscriptions(publish_topic, shared_filters, visit))
+    }
+}
+
+/// A set of writes that is applied at once, dropping the batch without committing it discards the
+/// writes. Subscriptions are checked against the limits when they are added to the batch, counting
+/// the writes of deferred commits that are not published yet. Removals in the same batch or in
+/// unpublished commits don't free up quota for them.
+pub struct Batch<'a> {
+    writer: MutexGuard<'a, TopicTreeWriter>,
+    operations: Vec<TopicTreeOperations>,
+    pending_subscriptions: HashMap<ClientId, usize>,
+    pending_nodes: usize,
+    #[cfg(feature = "metrics")]
+    recorder: Option<Arc<dyn MetricsRecorder>>,
+}
+
+impl Batch<'_> {
+    pub fn add_subscription(
+        &mut self,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+        qos: QoS,
+    ) -> Result<(), SubscriptionError> {
+        let pending_subscriptions = self.pending_subscriptions.entry(client_id).or_insert(0);
+        let writer = &*self.writer;
+        let unpublished_subscriptions = writer.unpublished_subscriptions.get(&client_id);
+        let new_nodes = writer.write_handle.enter().unwrap().check_pending_subscription(
+            &topic_filter,
+            client_id,
+            *pending_subscriptions + unpublished_subscriptions.copied().unwrap_or(0),
+            self.pending_nodes + writer.unpublished_nodes,
+        )?;
+        if let Some(new_nodes) = new_nodes {
+            *pending_subscriptions += 1;
+            self.pending_nodes += new_nodes;
+        }
+        self.operations.push(AddSubscription(topic_filter, client_id, qos));
+        Ok(())
+    }
+
+    pub fn remove_subscription(&mut self, topic_filter: TopicFilter, client_id: ClientId) {
+        self.operations.push(RemoveSubscription(topic_filter, client_id));
+    }
+
+    /// The published tree, which holds every write before this batch if there are no deferred
+    /// commits
+    pub(crate) fn tree(&self) -> TreeReadGuard<'_> {
+        self.writer.write_handle.enter().unwrap()
+    }
+
+    pub fn remove_client(&mut self, client_id: ClientId) {
+        self.operations.push(RemoveClient(client_id));
+    }
+
+    /// Logs the writes if the tree is durable, then applies and publishes them. Returns the epoch
+    /// of the writes, which are visible to readers when this returns.
+    pub fn commit(mut self) -> Result<Epoch, WriteError> {
+        self.finish(true)
+    }
+
+    /// Commits the writes like [`commit`](Self::commit), and reads the tree they were applied to
+    /// before the write lock is released
+    pub(crate) fn commit_then<T>(
+        mut self,
+        f: impl FnOnce(&TopicTree) -> T,
+    ) -> Result<(Epoch, T), WriteError> {
+        let epoch = self.finish(true)?;
+        Ok((epoch, f(&self.tree())))
+    }
+
+    /// Logs and applies the writes like [`commit`](Self::commit), but leaves publishing them to a
+    /// later commit, [`MqttTopicTree::publish`] or [`MqttTopicTree::wait_visible`]. This spreads
+    /// the cost of waiting for the readers over several batches.
+    pub fn commit_deferred(mut self) -> Result<Epoch, WriteError> {
+        self.finish(false)
+    }
+
+    fn finish(&mut self, publish: bool) -> Result<Epoch, WriteError> {
+        let writer = &mut *self.writer;
+        if self.operations.is_empty() {
+            return Ok(Epoch(writer.epoch));
+        };
+        #[cfg(feature = "metrics")]
+        let (start, operations) = (Instant::now(), self.operations.len());
+        if let Some(wal) = writer.wal.as_mut() {
+            wal.append(&self.operations).map_err(WriteError::Log)?;
+        }
+        for operation in self.operations.drain(..) {
+            writer.write_handle.append(operation);
+        }
+        writer.epoch += 1;
+        for (client_id, count) in self.pending_subscriptions.drain() {
+            *writer.unpublished_subscriptions.entry(client_id).or_insert(0) += count;
+        }
+        writer.unpublished_nodes += self.pending_nodes;
+        if publish {
+            #[cfg(feature = "metrics")]
+            let publish_start = Instant::now();
+            writer.publish();
+            #[cfg(feature = "metrics")]
+            if let Some(recorder) = self.recorder.as_deref() {
+                recorder.record_publish_wait(publish_start.elapsed());
+            }
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(recorder) = self.recorder.as_deref() {
+            recorder.record_write(start.elapsed(), operations);
+        }
+        Ok(Epoch(writer.epoch))
+    }
+}
+
+pub enum TopicIndexOperations {
+    InsertTopic(TopicName),
+    RemoveTopic(TopicName),
+}
+
+impl Absorb<TopicIndexOperations> for TopicIndex {
+    fn absorb_first(&mut self, operation: &mut TopicIndexOperations, _: &Self) {
+        match operation {
+            InsertTopic(topic_name) => {
+                self.insert(topic_name.clone());
+            }
+            RemoveTopic(topic_name) => {
+                self.remove(topic_name);
+            }
+        }
+    }
+
+    fn sync_with(&mut self, first: &Self) {
+        *self = first.clone();
+    }
+}
+
+/// A TopicIndex that can be shared between threads, lookups never block on writes
+#[derive(Clone)]
+pub struct MqttTopicIndex {
+    read_handle: ReadHandle<TopicIndex>,
+    write_handle: Arc<Mutex<WriteHandle<TopicIndex, TopicIndexOperations>>>
+}
+
+impl Default for MqttTopicIndex {
+    fn default() -> Self {
+        let (write, read) = left_right::new::<TopicIndex, TopicIndexOperations>();
+        Self {
+            read_handle: read,
+            write_handle: Arc::new(Mutex::new(write)),
+        }
+    }
+}
+
+impl MqttTopicIndex {
+    pub fn insert(&self, topic_name: TopicName) {
         let mut write_handle = self.write_handle.lock();
-        let operation = RemoveSubscription(topic_filter, client_id);
-        write_handle.append(operation);
+        write_handle.append(InsertTopic(topic_name));
         write_handle.publish();
     }
 
-    pub fn get_subscriptions(&self, publish_topic: &TopicName) -> Vec<Subscriber> {
+    pub fn remove(&self, topic_name: TopicName) {
+        let mut write_handle = self.write_handle.lock();
+        write_handle.append(RemoveTopic(topic_name));
+        write_handle.publish();
+    }
+
+    pub fn matching(&self, topic_filter: &TopicFilter) -> Vec<TopicName> {
         let a = self.read_handle.enter().unwrap();
-        a.get_subscriptions(publish_topic)
+        a.matching(topic_filter)
     }
 }
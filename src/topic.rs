@@ -1,5 +1,50 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
+/// How strictly the characters of a topic are checked against the MQTT encoding rules.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Only reject what the spec says MUST NOT appear: U+0000 and malformed UTF-8 (which includes
+    /// encoded UTF-16 surrogates).
+    #[default]
+    Lenient,
+    /// Also reject what the spec says SHOULD NOT appear: the control characters U+0001..U+001F and
+    /// U+007F..U+009F, and Unicode non-characters.
+    Strict,
+}
+
+/// The reason a single character is not allowed in a topic
+enum CharViolation {
+    Null,
+    ControlCharacter,
+    NonCharacter,
+}
+
+fn check_char(c: char, strictness: Strictness) -> Result<(), CharViolation> {
+    if c == '\0' {
+        return Err(CharViolation::Null);
+    };
+    if strictness == Strictness::Lenient {
+        return Ok(());
+    };
+    if c.is_control() {
+        return Err(CharViolation::ControlCharacter);
+    };
+    let code_point = c as u32;
+    if (0xFDD0..=0xFDEF).contains(&code_point) || code_point & 0xFFFE == 0xFFFE {
+        return Err(CharViolation::NonCharacter);
+    };
+    Ok(())
+}
+
+/// Whether the string can be a level of a topic filter other than a wildcard, checked with the
+/// same rules as `TopicFilter::try_from`
+pub(crate) fn is_literal_level(level: &str) -> bool {
+    !level.contains(['/', '+', '#'])
+        && level.chars().all(|c| check_char(c, Strictness::default()).is_ok())
+}
+
 /// A Struct for searching through the topic tree
 #[derive(Clone)]
 pub struct TopicName {
@@ -9,21 +54,8 @@ pub struct TopicName {
 }
 
 impl TopicName {
-    pub fn get_part(&self, index: usize) -> Option<&str> {
-        if index >= self.length {
-            return None;
-        };
-        let startidx = self.topic_indices[index].0;
-        let endindex = self.topic_indices[index].1;
-        let ret = &self.orig_str[startidx..endindex];
-        Some(ret)
-    }
-}
-
-impl TryFrom<String> for TopicName {
-    type Error = TopicNameError;
-
-    fn try_from(value: String) -> Result<Self, Self::Error> {
+    /// Parses a topic name, checking its characters with the given strictness
+    pub fn new(value: String, strictness: Strictness) -> Result<Self, TopicNameError> {
         let orig_str = Arc::new(value.clone());
         let str_max_chars = value.len();
         if str_max_chars == 0 {
@@ -35,15 +67,23 @@ impl TryFrom<String> for TopicName {
         let mut topic_indices = Vec::with_capacity(str_max_chars);
         let mut prev_slice = 0;
         for (idx, c) in value.char_indices() {
+            let position = idx;
             if c == '#' {
-                return Err(TopicNameError::ContainsMultiLevelWildcard);
+                return Err(TopicNameError::ContainsMultiLevelWildcard { position });
             };
             if c == '+' {
-                return Err(TopicNameError::ContiansSingleLevelWildcard);
-            };
-            if c == '\0' {
-                return Err(TopicNameError::ContainsNull);
+                return Err(TopicNameError::ContainsSingleLevelWildcard { position });
             };
+            match check_char(c, strictness) {
+                Ok(()) => {}
+                Err(CharViolation::Null) => return Err(TopicNameError::ContainsNull { position }),
+                Err(CharViolation::ControlCharacter) => {
+                    return Err(TopicNameError::ContainsControlCharacter { position })
+                }
+                Err(CharViolation::NonCharacter) => {
+                    return Err(TopicNameError::ContainsNonCharacter { position })
+                }
+            }
             if c == '/' {
                 topic_indices.push((prev_slice, idx));
                 prev_slice = idx + 1;
@@ -58,17 +98,153 @@ impl TryFrom<String> for TopicName {
             orig_str,
         })
     }
+
+    /// Parses a topic name straight from the bytes received on the wire
+    pub fn from_bytes(value: &[u8], strictness: Strictness) -> Result<Self, TopicNameError> {
+        match std::str::from_utf8(value) {
+            Ok(value) => Self::new(value.to_owned(), strictness),
+            Err(e) => Err(TopicNameError::InvalidUtf8 { position: e.valid_up_to() }),
+        }
+    }
+
+    /// The topic name as it was received
+    pub fn as_str(&self) -> &str {
+        &self.orig_str
+    }
+
+    /// The number of levels in the topic name
+    pub fn levels(&self) -> usize {
+        self.length
+    }
+
+    pub fn get_part(&self, index: usize) -> Option<&str> {
+        if index >= self.length {
+            return None;
+        };
+        let startidx = self.topic_indices[index].0;
+        let endindex = self.topic_indices[index].1;
+        let ret = &self.orig_str[startidx..endindex];
+        Some(ret)
+    }
 }
 
+impl TryFrom<String> for TopicName {
+    type Error = TopicNameError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::new(value, Strictness::default())
+    }
+}
+
+impl TryFrom<&str> for TopicName {
+    type Error = TopicNameError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::new(value.to_owned(), Strictness::default())
+    }
+}
+
+impl PartialEq for TopicName {
+    fn eq(&self, other: &Self) -> bool {
+        self.orig_str == other.orig_str
+    }
+}
+
+impl Eq for TopicName {}
+
+impl Hash for TopicName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.orig_str.hash(state)
+    }
+}
+
+impl AsRef<str> for TopicName {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for TopicName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for TopicName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TopicName").field(&self.as_str()).finish()
+    }
+}
+
+/// The MQTT 5 reason code for an invalid topic name
+pub const REASON_TOPIC_NAME_INVALID: u8 = 0x90;
+/// The MQTT 5 reason code for an invalid topic filter
+pub const REASON_TOPIC_FILTER_INVALID: u8 = 0x8F;
+/// The MQTT 5 reason code for a shared subscription on a server that does not support them
+pub const REASON_SHARED_SUBSCRIPTIONS_NOT_SUPPORTED: u8 = 0x9E;
+
+/// The reason a topic name was rejected, positions are byte offsets into the original string
 #[derive(Debug)]
 pub enum TopicNameError {
-    ContainsMultiLevelWildcard,
-    ContiansSingleLevelWildcard,
-    ContainsNull,
+    ContainsMultiLevelWildcard { position: usize },
+    ContainsSingleLevelWildcard { position: usize },
+    ContainsNull { position: usize },
+    ContainsControlCharacter { position: usize },
+    ContainsNonCharacter { position: usize },
+    InvalidUtf8 { position: usize },
     IsEmpty,
     TooLong,
 }
 
+impl TopicNameError {
+    /// The byte offset of the offending character, if the error is caused by a single character
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            TopicNameError::ContainsMultiLevelWildcard { position }
+            | TopicNameError::ContainsSingleLevelWildcard { position }
+            | TopicNameError::ContainsNull { position }
+            | TopicNameError::ContainsControlCharacter { position }
+            | TopicNameError::ContainsNonCharacter { position }
+            | TopicNameError::InvalidUtf8 { position } => Some(*position),
+            TopicNameError::IsEmpty | TopicNameError::TooLong => None,
+        }
+    }
+
+    /// The MQTT 5 reason code to send back to the client
+    pub fn reason_code(&self) -> u8 {
+        REASON_TOPIC_NAME_INVALID
+    }
+}
+
+impl fmt::Display for TopicNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicNameError::ContainsMultiLevelWildcard { position } => {
+                write!(f, "topic name contains a multi-level wildcard at byte {position}")
+            }
+            TopicNameError::ContainsSingleLevelWildcard { position } => {
+                write!(f, "topic name contains a single-level wildcard at byte {position}")
+            }
+            TopicNameError::ContainsNull { position } => {
+                write!(f, "topic name contains U+0000 at byte {position}")
+            }
+            TopicNameError::ContainsControlCharacter { position } => {
+                write!(f, "topic name contains a control character at byte {position}")
+            }
+            TopicNameError::ContainsNonCharacter { position } => {
+                write!(f, "topic name contains a unicode non-character at byte {position}")
+            }
+            TopicNameError::InvalidUtf8 { position } => {
+                write!(f, "topic name is not valid UTF-8 at byte {position}")
+            }
+            TopicNameError::IsEmpty => write!(f, "topic name is empty"),
+            TopicNameError::TooLong => write!(f, "topic name is longer than {} bytes", u16::MAX),
+        }
+    }
+}
+
+impl std::error::Error for TopicNameError {}
+
 #[derive(Clone)]
 pub struct TopicFilter {
     pub(crate) length: usize,
@@ -78,21 +254,8 @@ pub struct TopicFilter {
 }
 
 impl TopicFilter {
-    pub fn get_part(&self, index: usize) -> Option<&str> {
-        if index >= self.length {
-            return None;
-        };
-        let startidx = self.topic_indices[index].0;
-        let endindex = self.topic_indices[index].1;
-        let ret = &self.orig_str[startidx..endindex];
-        Some(ret)
-    }
-}
-
-impl TryFrom<String> for TopicFilter {
-    type Error = TopicFilterError;
-
-    fn try_from(value: String) -> Result<Self, Self::Error> {
+    /// Parses a topic filter, checking its characters with the given strictness
+    pub fn new(value: String, strictness: Strictness) -> Result<Self, TopicFilterError> {
         let orig_str = Arc::new(value.clone());
         let str_max_chars = value.len();
         if str_max_chars == 0 {
@@ -104,8 +267,24 @@ impl TryFrom<String> for TopicFilter {
         let mut topic_indices = Vec::with_capacity(str_max_chars);
         let mut prev_slice = 0;
         for (idx, c) in value.char_indices() {
-            if c == '\0' {
-                return Err(TopicFilterError::ContainsNull);
+            let position = idx;
+            match check_char(c, strictness) {
+                Ok(()) => {}
+                Err(CharViolation::Null) => return Err(TopicFilterError::ContainsNull { position }),
+                Err(CharViolation::ControlCharacter) => {
+                    return Err(TopicFilterError::ContainsControlCharacter { position })
+                }
+                Err(CharViolation::NonCharacter) => {
+                    return Err(TopicFilterError::ContainsNonCharacter { position })
+                }
+            }
+            // Wildcards have to occupy a whole level, and the multi-level wildcard has to be last
+            let ends_level = matches!(value.as_bytes().get(idx + 1), None | Some(b'/'));
+            if c == '+' && (idx != prev_slice || !ends_level) {
+                return Err(TopicFilterError::InvalidSingleLevelWildcard { position });
+            };
+            if c == '#' && (idx != prev_slice || idx + 1 != str_max_chars) {
+                return Err(TopicFilterError::InvalidMultiLevelWildcard { position });
             };
             if c == '/' {
                 topic_indices.push((prev_slice, idx));
@@ -119,10 +298,20 @@ impl TryFrom<String> for TopicFilter {
         let endindex = topic_indices[0].1;
         let first_level = &value[startidx..endindex];
         if first_level == "$share" {
+            // A share name has to be followed by a non empty topic filter
+            let has_filter = topic_indices.len() > 3
+                || (topic_indices.len() == 3 && topic_indices[2].0 != str_max_chars);
+            if !has_filter {
+                return Err(TopicFilterError::InvalidSharedSubscription);
+            };
             let startidx = topic_indices[1].0;
             let endindex = topic_indices[1].1;
-            shared_group_name = Some(value[startidx..endindex].to_owned());
-            topic_indices = topic_indices[2..].iter().map(|x| *x).collect();
+            let group_name = &value[startidx..endindex];
+            if group_name.is_empty() || group_name.contains(['+', '#']) {
+                return Err(TopicFilterError::InvalidSharedSubscription);
+            };
+            shared_group_name = Some(group_name.to_owned());
+            topic_indices = topic_indices[2..].to_vec();
         }
         let length = topic_indices.len();
 
@@ -133,11 +322,308 @@ impl TryFrom<String> for TopicFilter {
             orig_str
         })
     }
+
+    /// Parses a topic filter straight from the bytes received on the wire
+    pub fn from_bytes(value: &[u8], strictness: Strictness) -> Result<Self, TopicFilterError> {
+        match std::str::from_utf8(value) {
+            Ok(value) => Self::new(value.to_owned(), strictness),
+            Err(e) => Err(TopicFilterError::InvalidUtf8 { position: e.valid_up_to() }),
+        }
+    }
+
+    /// The topic filter as it was received, including the `$share/<group>/` prefix
+    pub fn as_str(&self) -> &str {
+        &self.orig_str
+    }
+
+    /// The topic filter without the `$share/<group>/` prefix
+    pub fn filter_str(&self) -> &str {
+        match self.topic_indices.first() {
+            Some((startidx, _)) => &self.orig_str[*startidx..],
+            None => "",
+        }
+    }
+
+    /// The group of a shared subscription
+    pub fn shared_group(&self) -> Option<&str> {
+        self.shared_group_name.as_deref()
+    }
+
+    /// The number of levels in the topic filter, excluding the `$share/<group>` prefix
+    pub fn levels(&self) -> usize {
+        self.length
+    }
+
+    /// Checks whether the filter matches a topic name, following the same rules as the TopicTree:
+    /// `+` matches exactly one level, `#` matches its parent and any number of levels below it, and
+    /// topics starting with `$` are never matched by a wildcard in the first level.
+    pub fn matches(&self, topic: &TopicName) -> bool {
+        let first_level = topic.get_part(0).unwrap();
+        if first_level.starts_with('$') && matches!(self.get_part(0), Some("+") | Some("#")) {
+            return false;
+        };
+        for i in 0..self.length {
+            match self.get_part(i).unwrap() {
+                "#" => return true,
+                "+" => {
+                    if i >= topic.length {
+                        return false;
+                    }
+                }
+                filter_level => {
+                    if topic.get_part(i) != Some(filter_level) {
+                        return false;
+                    }
+                }
+            }
+        }
+        self.length == topic.length
+    }
+
+    /// Checks whether every topic matched by this filter is also matched by the other filter.
+    /// Only the filters are compared, the `$share/<group>/` prefixes are ignored.
+    pub fn is_subset_of(&self, other: &TopicFilter) -> bool {
+        for i in 0..self.length {
+            let self_level = self.get_part(i).unwrap();
+            let Some(other_level) = other.get_part(i) else {
+                return false;
+            };
+            match (self_level, other_level) {
+                // A wildcard in the first level never matches topics starting with `$`
+                (_, "#") => return !(i == 0 && self_level.starts_with('$')),
+                // There is no empty parent level for `#` to match in the first level, so `+/#`
+                // matches the same topics
+                ("#", "+") => return i == 0 && other.get_part(1) == Some("#"),
+                ("#", _) => return false,
+                ("+", "+") => {}
+                ("+", _) => return false,
+                (_, "+") => {
+                    if i == 0 && self_level.starts_with('$') {
+                        return false;
+                    }
+                }
+                _ => {
+                    if self_level != other_level {
+                        return false;
+                    }
+                }
+            }
+        }
+        // `#` also matches its parent level
+        self.length == other.length || other.get_part(self.length) == Some("#")
+    }
+
+    /// Checks whether there is a topic that is matched by both filters
+    pub fn overlaps(&self, other: &TopicFilter) -> bool {
+        self.intersection(other).is_some()
+    }
+
+    /// The filter that matches exactly the topics matched by both filters, or None if no topic is
+    /// matched by both. The result is never a shared subscription.
+    pub fn intersection(&self, other: &TopicFilter) -> Option<TopicFilter> {
+        let mut levels: Vec<&str> = Vec::with_capacity(self.length.max(other.length));
+        let mut i = 0;
+        loop {
+            match (self.get_part(i), other.get_part(i)) {
+                (None, None) => break,
+                (Some("#"), None) | (None, Some("#")) => break,
+                (Some("#"), Some(_)) => {
+                    levels.extend((i..other.length).map(|x| other.get_part(x).unwrap()));
+                    break;
+                }
+                (Some(_), Some("#")) => {
+                    levels.extend((i..self.length).map(|x| self.get_part(x).unwrap()));
+                    break;
+                }
+                (None, Some(_)) | (Some(_), None) => return None,
+                (Some("+"), Some(level)) | (Some(level), Some("+")) => levels.push(level),
+                (Some(self_level), Some(other_level)) => {
+                    if self_level != other_level {
+                        return None;
+                    }
+                    levels.push(self_level);
+                }
+            }
+            i += 1;
+        }
+        // A wildcard in the first level never matches topics starting with `$`
+        let first_is_wildcard =
+            |x: &TopicFilter| matches!(x.get_part(0), Some("+") | Some("#"));
+        let dollar_topic = levels.first().is_some_and(|x| x.starts_with('$'));
+        if dollar_topic && (first_is_wildcard(self) || first_is_wildcard(other)) {
+            return None;
+        };
+        Some(TopicFilter::from_levels(None, &levels))
+    }
+
+    /// Builds a filter from levels that are already known to be valid, like the path to a node of
+    /// a tree, without parsing it again
+    pub(crate) fn from_levels(shared_group: Option<&str>, levels: &[&str]) -> TopicFilter {
+        let mut orig_str = match shared_group {
+            Some(group_name) => format!("$share/{group_name}/"),
+            None => String::new(),
+        };
+        let mut startidx = orig_str.len();
+        orig_str.push_str(&levels.join("/"));
+        let mut topic_indices = Vec::with_capacity(levels.len());
+        for level in levels {
+            topic_indices.push((startidx, startidx + level.len()));
+            startidx += level.len() + 1;
+        }
+        TopicFilter {
+            length: levels.len(),
+            shared_group_name: shared_group.map(str::to_owned),
+            topic_indices,
+            orig_str: Arc::new(orig_str),
+        }
+    }
+
+    /// Rejects shared subscriptions, for servers that do not support them
+    pub fn ensure_unshared(&self) -> Result<(), TopicFilterError> {
+        match self.shared_group_name {
+            None => Ok(()),
+            Some(_) => Err(TopicFilterError::SharedSubscriptionsNotSupported),
+        }
+    }
+
+    pub fn get_part(&self, index: usize) -> Option<&str> {
+        if index >= self.length {
+            return None;
+        };
+        let startidx = self.topic_indices[index].0;
+        let endindex = self.topic_indices[index].1;
+        let ret = &self.orig_str[startidx..endindex];
+        Some(ret)
+    }
+}
+
+impl TryFrom<String> for TopicFilter {
+    type Error = TopicFilterError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::new(value, Strictness::default())
+    }
+}
+
+impl TryFrom<&str> for TopicFilter {
+    type Error = TopicFilterError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::new(value.to_owned(), Strictness::default())
+    }
+}
+
+/// Two filters are equal when both the filter and the shared group are the same
+impl PartialEq for TopicFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.shared_group_name == other.shared_group_name && self.filter_str() == other.filter_str()
+    }
 }
 
+impl Eq for TopicFilter {}
+
+impl Hash for TopicFilter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.shared_group_name.hash(state);
+        self.filter_str().hash(state);
+    }
+}
+
+impl AsRef<str> for TopicFilter {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TopicFilter").field(&self.as_str()).finish()
+    }
+}
+
+/// The reason a topic filter was rejected, positions are byte offsets into the original string
 #[derive(Debug)]
 pub enum TopicFilterError {
-    ContainsNull,
+    ContainsNull { position: usize },
+    ContainsControlCharacter { position: usize },
+    ContainsNonCharacter { position: usize },
+    InvalidUtf8 { position: usize },
+    InvalidSingleLevelWildcard { position: usize },
+    InvalidMultiLevelWildcard { position: usize },
+    InvalidSharedSubscription,
+    SharedSubscriptionsNotSupported,
     IsEmpty,
     TooLong,
 }
+
+impl TopicFilterError {
+    /// The byte offset of the offending character, if the error is caused by a single character
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            TopicFilterError::ContainsNull { position }
+            | TopicFilterError::ContainsControlCharacter { position }
+            | TopicFilterError::ContainsNonCharacter { position }
+            | TopicFilterError::InvalidUtf8 { position }
+            | TopicFilterError::InvalidSingleLevelWildcard { position }
+            | TopicFilterError::InvalidMultiLevelWildcard { position } => Some(*position),
+            TopicFilterError::InvalidSharedSubscription
+            | TopicFilterError::SharedSubscriptionsNotSupported
+            | TopicFilterError::IsEmpty
+            | TopicFilterError::TooLong => None,
+        }
+    }
+
+    /// The MQTT 5 reason code to send back to the client
+    pub fn reason_code(&self) -> u8 {
+        match self {
+            TopicFilterError::SharedSubscriptionsNotSupported => {
+                REASON_SHARED_SUBSCRIPTIONS_NOT_SUPPORTED
+            }
+            _ => REASON_TOPIC_FILTER_INVALID,
+        }
+    }
+}
+
+impl fmt::Display for TopicFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopicFilterError::ContainsNull { position } => {
+                write!(f, "topic filter contains U+0000 at byte {position}")
+            }
+            TopicFilterError::ContainsControlCharacter { position } => {
+                write!(f, "topic filter contains a control character at byte {position}")
+            }
+            TopicFilterError::ContainsNonCharacter { position } => {
+                write!(f, "topic filter contains a unicode non-character at byte {position}")
+            }
+            TopicFilterError::InvalidUtf8 { position } => {
+                write!(f, "topic filter is not valid UTF-8 at byte {position}")
+            }
+            TopicFilterError::InvalidSingleLevelWildcard { position } => {
+                write!(f, "single-level wildcard at byte {position} does not occupy a whole level")
+            }
+            TopicFilterError::InvalidMultiLevelWildcard { position } => write!(
+                f,
+                "multi-level wildcard at byte {position} is not the whole last level"
+            ),
+            TopicFilterError::InvalidSharedSubscription => {
+                write!(f, "shared subscription has an invalid share name or no topic filter")
+            }
+            TopicFilterError::SharedSubscriptionsNotSupported => {
+                write!(f, "shared subscriptions are not supported")
+            }
+            TopicFilterError::IsEmpty => write!(f, "topic filter is empty"),
+            TopicFilterError::TooLong => {
+                write!(f, "topic filter is longer than {} bytes", u16::MAX)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TopicFilterError {}
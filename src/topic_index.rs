@@ -0,0 +1,132 @@
+use crate::{TopicFilter, TopicName};
+use std::collections::HashMap;
+
+/// The TopicIndex answers the reverse question of the TopicTree: it stores concrete topic names and
+/// finds all of them that are matched by a topic filter, e.g. to replay retained messages to a new
+/// subscription.
+#[derive(Default, Debug, Clone)]
+pub struct TopicIndex {
+    root_node: IndexNode,
+    topics: usize,
+}
+
+impl TopicIndex {
+    /// Adds a topic, returns whether it was not in the index yet
+    pub fn insert(&mut self, topic_name: TopicName) -> bool {
+        let mut curr_node = &mut self.root_node;
+        for i in 0..topic_name.length {
+            let topic_level = topic_name.get_part(i).unwrap();
+            curr_node = curr_node.get_sub_node_or_create(topic_level);
+        }
+        let added = curr_node.topic.is_none();
+        curr_node.topic = Some(topic_name);
+        self.topics += added as usize;
+        added
+    }
+
+    /// Removes a topic, returns whether it was in the index
+    pub fn remove(&mut self, topic_name: &TopicName) -> bool {
+        let removed = self.root_node.remove(topic_name, 0);
+        self.topics -= removed as usize;
+        removed
+    }
+
+    pub fn contains(&self, topic_name: &TopicName) -> bool {
+        let mut curr_node = &self.root_node;
+        for i in 0..topic_name.length {
+            match curr_node.sub_nodes.get(topic_name.get_part(i).unwrap()) {
+                Some(node) => curr_node = node,
+                None => return false,
+            }
+        }
+        curr_node.topic.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.topics
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics == 0
+    }
+
+    /// Finds all topics that are matched by the filter, following the same rules as
+    /// [`TopicFilter::matches`]. Literal levels are single lookups, so the work done is proportional
+    /// to the number of nodes under the wildcards of the filter rather than the size of the index.
+    pub fn matching(&self, topic_filter: &TopicFilter) -> Vec<TopicName> {
+        let mut results = Vec::new();
+        self.root_node.matching(topic_filter, 0, &mut results);
+        results
+    }
+}
+
+/// The IndexNode has the same layout as the TopicNode, but it can only be keyed by concrete levels
+#[derive(Default, Debug, Clone)]
+struct IndexNode {
+    sub_nodes: HashMap<String, IndexNode>,
+    topic: Option<TopicName>,
+}
+
+impl IndexNode {
+    fn matching(&self, topic_filter: &TopicFilter, level: usize, results: &mut Vec<TopicName>) {
+        let Some(topic_level) = topic_filter.get_part(level) else {
+            results.extend(self.topic.clone());
+            return;
+        };
+        // Topics starting with `$` are never matched by a wildcard in the first level
+        let skip_dollar = |x: &String| level == 0 && x.starts_with('$');
+        match topic_level {
+            "#" => {
+                // `#` also matches its parent level
+                results.extend(self.topic.clone());
+                for (sub_level, sub_node) in self.sub_nodes.iter() {
+                    if !skip_dollar(sub_level) {
+                        sub_node.collect_all(results);
+                    }
+                }
+            }
+            "+" => {
+                for (sub_level, sub_node) in self.sub_nodes.iter() {
+                    if !skip_dollar(sub_level) {
+                        sub_node.matching(topic_filter, level + 1, results);
+                    }
+                }
+            }
+            _ => {
+                if let Some(sub_node) = self.sub_nodes.get(topic_level) {
+                    sub_node.matching(topic_filter, level + 1, results);
+                }
+            }
+        }
+    }
+
+    fn collect_all(&self, results: &mut Vec<TopicName>) {
+        results.extend(self.topic.clone());
+        for sub_node in self.sub_nodes.values() {
+            sub_node.collect_all(results);
+        }
+    }
+
+    /// Returns whether the topic was removed, nodes that no longer lead to a topic are pruned
+    fn remove(&mut self, topic_name: &TopicName, level: usize) -> bool {
+        let Some(topic_level) = topic_name.get_part(level) else {
+            return self.topic.take().is_some();
+        };
+        let Some(sub_node) = self.sub_nodes.get_mut(topic_level) else {
+            return false;
+        };
+        let removed = sub_node.remove(topic_name, level + 1);
+        if sub_node.topic.is_none() && sub_node.sub_nodes.is_empty() {
+            self.sub_nodes.remove(topic_level);
+        }
+        removed
+    }
+
+    fn get_sub_node_or_create(&mut self, topic_level: &str) -> &mut Self {
+        if !self.sub_nodes.contains_key(topic_level) {
+            self.sub_nodes
+                .insert(topic_level.to_owned(), IndexNode::default());
+        }
+        self.sub_nodes.get_mut(topic_level).unwrap()
+    }
+}
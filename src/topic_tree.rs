@@ -1,261 +1,836 @@
+use crate::events::{SubscriptionEvent, SubscriptionListener};
+use crate::intern::{Interner, LevelMap, remove_level};
+use crate::limits::{SubscriptionError, TopicLimits};
 use crate::{ClientId, QoS, TopicFilter, TopicName};
 use rand::random;
 use std::collections::HashMap;
+use std::hash::RandomState;
 use std::ops::DerefMut;
+use std::sync::Arc;
+
+/// A map in the tree, under the `rcu` feature it is a persistent map that the versions of the
+/// tree share
+#[cfg(not(feature = "rcu"))]
+pub(crate) type NodeMap<K, V, S> = HashMap<K, V, S>;
+#[cfg(feature = "rcu")]
+pub(crate) type NodeMap<K, V, S> = imbl::GenericHashMap<K, V, S, imbl::shared_ptr::DefaultSharedPtr>;
+
+/// A boxed part of a node, under the `rcu` feature it is shared between versions of the tree
+/// and copied when a version changes it
+#[cfg(not(feature = "rcu"))]
+pub(crate) type Shared<T> = Box<T>;
+#[cfg(feature = "rcu")]
+pub(crate) type Shared<T> = crate::rcu::CowArc<T>;
+
+/// The subscriptions of a node, shared like [`Shared`] under the `rcu` feature
+#[cfg(not(feature = "rcu"))]
+pub(crate) type Content = SubscriptionInfo;
+#[cfg(feature = "rcu")]
+pub(crate) type Content = crate::rcu::CowArc<SubscriptionInfo>;
+
+/// Sees the levels of the filter and the SubscriptionInfo of every match of a lookup
+pub(crate) type MatchVisitor<'v> = &'v mut dyn FnMut(&[&str], &SubscriptionInfo);
+
+/// The number of entries a map has room for
+pub(crate) trait Slots {
+    fn slots(&self) -> usize;
+}
+
+impl<K, V, S> Slots for HashMap<K, V, S> {
+    fn slots(&self) -> usize {
+        self.capacity()
+    }
+}
+
+#[cfg(feature = "rcu")]
+impl<K, V, S> Slots for NodeMap<K, V, S> {
+    fn slots(&self) -> usize {
+        self.len()
+    }
+}
 
 /// The TopicTree is a tree structure containing all the routing information for the subscribers
 /// Subscriptions are added or removed from this structure and all clients that are subscribed to a
 /// topic can be queried from here
 #[derive(Default, Debug, Clone)]
 pub struct TopicTree {
-    root_node: TopicNode,
-    subscribers: u64,
+    pub(crate) root_node: TopicNode,
+    pub(crate) subscribers: u64,
+    pub(crate) nodes: usize,
+    pub(crate) client_subscriptions: NodeMap<ClientId, usize, RandomState>,
+    pub(crate) limits: TopicLimits,
+    pub(crate) listener: Option<SubscriptionListener>,
+    pub(crate) interner: Interner,
 }
 
 impl TopicTree {
+    pub fn with_limits(limits: TopicLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    pub fn limits(&self) -> &TopicLimits {
+        &self.limits
+    }
+
+    /// Replaces the limits, they only apply to subscriptions that are added afterwards
+    pub fn set_limits(&mut self, limits: TopicLimits) {
+        self.limits = limits;
+    }
+
+    /// Sets the callback that receives a SubscriptionEvent for every change the tree makes, the
+    /// events are emitted while each operation is applied. Removing a client walks its
+    /// subscriptions one by one while a listener is set.
+    pub fn set_listener<F>(&mut self, listener: F)
+    where
+        F: Fn(&SubscriptionEvent) + Send + Sync + 'static,
+    {
+        self.listener = Some(SubscriptionListener(Arc::new(listener)));
+    }
+
+    pub fn clear_listener(&mut self) {
+        self.listener = None;
+    }
+
+    /// A version of the tree for readers that shares all nodes with this one, and is never
+    /// changed
+    #[cfg(feature = "rcu")]
+    pub(crate) fn published_copy(&self) -> Self {
+        Self {
+            root_node: self.root_node.clone(),
+            subscribers: self.subscribers,
+            nodes: self.nodes,
+            client_subscriptions: self.client_subscriptions.clone(),
+            limits: self.limits.clone(),
+            listener: None,
+            interner: self.interner.frozen(),
+        }
+    }
+
+    fn emit(&self, event: SubscriptionEvent) {
+        if let Some(listener) = self.listener.as_ref() {
+            (listener.0)(&event);
+        }
+    }
+
+    /// Whether the filter has at least one subscriber
+    fn filter_active(&self, topic_filter: &TopicFilter) -> bool {
+        self.root_node
+            .find_subscription_info(&self.interner, topic_filter)
+            .is_some_and(|sub_info| sub_info.filter_active(topic_filter))
+    }
+
     pub fn get_subscriptions(&self, publish_topic: &TopicName) -> Vec<Subscriber> {
         let mut results = Vec::with_capacity(self.subscribers as usize);
         // self.root_node
-        //     .get_subscriptions_rec(0, publish_topic, &mut results);
-        // self.root_node
         //     .get_subscriptions(publish_topic, &mut results);
         self.root_node
-            .get_subscriptions_arr(publish_topic, &mut results);
+            .get_subscriptions_arr(&self.interner, publish_topic, &mut results);
+        results
+    }
+
+    /// Like [`get_subscriptions`](Self::get_subscriptions), with a visitor the tree is walked with
+    /// [`TopicNode::visit_matches`] so the visitor sees every match as well
+    pub(crate) fn find_subscriptions(
+        &self,
+        publish_topic: &TopicName,
+        visit: Option<MatchVisitor>,
+    ) -> Vec<Subscriber> {
+        let Some(visit) = visit else {
+            return self.get_subscriptions(publish_topic);
+        };
+        let mut results = Vec::with_capacity(self.subscribers as usize);
+        let symbols = &self.interner;
+        self.root_node
+            .visit_matches(symbols, publish_topic, 0, &mut Vec::new(), &mut |levels, sub_info| {
+                visit(levels, sub_info);
+                results.extend(sub_info.get_subscriptions());
+            });
+        results
+    }
+
+    /// Like [`get_subscriptions`](Self::get_subscriptions), but every QoS is downgraded to the QoS of
+    /// the publish, which is the QoS the message has to be delivered with
+    pub fn get_subscriptions_with_qos(
+        &self,
+        publish_topic: &TopicName,
+        publish_qos: QoS,
+    ) -> Vec<Subscriber> {
+        with_qos(self.get_subscriptions(publish_topic), publish_qos)
+    }
+
+    /// Returns every client once when several of its subscriptions match the topic, with the
+    /// highest QoS of those subscriptions
+    pub fn get_subscriptions_deduplicated(&self, publish_topic: &TopicName) -> Vec<Subscriber> {
+        deduplicated(self.get_subscriptions(publish_topic))
+    }
+
+    /// Deduplicates the subscribers so the highest QoS of a client wins, and then downgrades that
+    /// QoS to the QoS of the publish
+    pub fn get_subscriptions_deduplicated_with_qos(
+        &self,
+        publish_topic: &TopicName,
+        publish_qos: QoS,
+    ) -> Vec<Subscriber> {
+        with_qos(self.get_subscriptions_deduplicated(publish_topic), publish_qos)
+    }
+
+    /// The subscriptions for a publish that was routed to this node by
+    /// [`ClusterRoutes`](crate::ClusterRoutes). Shared groups are only included for the shared
+    /// filters this node was picked for, the other groups are served by another node.
+    pub fn get_routed_subscriptions(
+        &self,
+        publish_topic: &TopicName,
+        shared_filters: &[TopicFilter],
+    ) -> Vec<Subscriber> {
+        self.find_routed_subscriptions(publish_topic, shared_filters, None)
+    }
+
+    /// Like [`get_routed_subscriptions`](Self::get_routed_subscriptions), the visitor sees every
+    /// match as well
+    pub(crate) fn find_routed_subscriptions(
+        &self,
+        publish_topic: &TopicName,
+        shared_filters: &[TopicFilter],
+        mut visit: Option<MatchVisitor>,
+    ) -> Vec<Subscriber> {
+        let mut results = Vec::new();
+        let symbols = &self.interner;
+        self.root_node
+            .visit_matches(symbols, publish_topic, 0, &mut Vec::new(), &mut |levels, sub_info| {
+                if let Some(visit) = visit.as_mut() {
+                    visit(levels, sub_info);
+                }
+                results.extend(
+                    sub_info
+                        .client_subscriptions
+                        .iter()
+                        .map(|(client_id, qos)| Subscriber { client_id: *client_id, qos: *qos }),
+                );
+                for group in sub_info.shared_subscriptions.iter() {
+                    let picked = shared_filters.iter().any(|x| {
+                        x.shared_group() == Some(group.group_id.as_str())
+                            && x.filter_str().split('/').eq(levels.iter().copied())
+                    });
+                    if picked {
+                        results.push(group.get_next_client());
+                    }
+                }
+            });
         results
     }
 
-    pub fn add_subscription(&mut self, topic_filter: TopicFilter, client_id: ClientId, qos: QoS) {
-        self.root_node.add_subscriber(topic_filter, client_id, qos);
-        self.subscribers += 1;
+    /// Every subscription in the tree, shared subscriptions are listed once per group member
+    pub(crate) fn subscriptions(&self) -> Subscriptions<'_> {
+        Subscriptions::new(&self.interner, Vec::new(), Some(Part::Node(&self.root_node)))
+    }
+
+    /// Iterates over every subscription in the tree as `(filter, client, qos, share group)`. The
+    /// filters are rebuilt from the path to their node, shared subscriptions keep their
+    /// `$share/<group>/` prefix and are listed once per group member. The order is not defined.
+    pub fn iter(&self) -> impl Iterator<Item = (TopicFilter, ClientId, QoS, Option<String>)> {
+        self.subscriptions().map(subscription_entry)
+    }
+
+    /// Iterates over the subscriptions whose filter starts with the levels of the prefix, e.g.
+    /// `home/` lists `home`, `home/#` and `home/+/light` but not `house/#`. Wildcards in the prefix
+    /// are compared as levels, so `home/+` only lists filters with a `+` at that level.
+    pub fn iter_under(
+        &self,
+        prefix: &str,
+    ) -> impl Iterator<Item = (TopicFilter, ClientId, QoS, Option<String>)> {
+        let prefix = prefix.strip_suffix('/').unwrap_or(prefix);
+        let symbols = &self.interner;
+        let mut levels: Vec<&str> = Vec::new();
+        let mut part = Some(Part::Node(&self.root_node));
+        if !prefix.is_empty() {
+            for topic_level in prefix.split('/') {
+                let Some(Part::Node(node)) = part else {
+                    part = None;
+                    break;
+                };
+                part = match topic_level {
+                    "#" => {
+                        levels.push("#");
+                        node.multi_level_wildcard.as_deref().map(Part::Wildcard)
+                    }
+                    "+" => {
+                        levels.push("+");
+                        node.single_level_wildcard.as_deref().map(Part::Node)
+                    }
+                    _ => symbols.get(topic_level).and_then(|x| node.sub_nodes.get_key_value(x)).map(
+                        |(topic_level, node)| {
+                            levels.push(symbols.name(topic_level));
+                            Part::Node(node)
+                        },
+                    ),
+                };
+            }
+        }
+        Subscriptions::new(symbols, levels, part).map(subscription_entry)
+    }
+
+    /// Checks a subscription against the limits of the tree without adding it
+    pub fn check_subscription(
+        &self,
+        topic_filter: &TopicFilter,
+        client_id: ClientId,
+    ) -> Result<(), SubscriptionError> {
+        self.check_pending_subscription(topic_filter, client_id, 0, 0)
+            .map(|_| ())
+    }
+
+    /// Checks a subscription as if the client already had `pending_subscriptions` more
+    /// subscriptions and the tree `pending_nodes` more nodes. Returns the number of nodes the
+    /// subscription would create, or None if it replaces an existing subscription.
+    pub(crate) fn check_pending_subscription(
+        &self,
+        topic_filter: &TopicFilter,
+        client_id: ClientId,
+        pending_subscriptions: usize,
+        pending_nodes: usize,
+    ) -> Result<Option<usize>, SubscriptionError> {
+        self.limits.check_filter(topic_filter)?;
+        let symbols = &self.interner;
+        // Replacing an existing subscription does not count against the quotas
+        if self.root_node.contains_subscriber(symbols, topic_filter, client_id) {
+            return Ok(None);
+        };
+        let client_subscriptions = self.client_subscriptions.get(&client_id).copied();
+        let client_subscriptions = client_subscriptions.unwrap_or(0) + pending_subscriptions;
+        let new_nodes = self.root_node.missing_nodes(symbols, topic_filter);
+        self.limits
+            .check_quota(client_subscriptions, self.nodes + pending_nodes + new_nodes)?;
+        Ok(Some(new_nodes))
+    }
+
+    /// Adds a subscription, replacing the QoS if the client is already subscribed to the filter
+    pub fn add_subscription(
+        &mut self,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+        qos: QoS,
+    ) -> Result<(), SubscriptionError> {
+        self.check_subscription(&topic_filter, client_id)?;
+        if self.listener.is_some() {
+            if !self.filter_active(&topic_filter) {
+                self.emit(SubscriptionEvent::FilterActivated(topic_filter.clone()));
+            }
+            self.emit(SubscriptionEvent::ClientSubscribed {
+                topic_filter: topic_filter.clone(),
+                client_id,
+                qos,
+            });
+        }
+        let (added, new_nodes) =
+            self.root_node
+                .add_subscriber(&mut self.interner, topic_filter, client_id, qos);
+        self.nodes += new_nodes;
+        if added {
+            self.subscribers += 1;
+            *self.client_subscriptions.entry(client_id).or_insert(0) += 1;
+        }
+        Ok(())
     }
 
+    /// Removes a subscription, returns whether the client was subscribed to the filter
     pub fn remove_subscription(
         &mut self,
         topic_filter: TopicFilter,
         client_id: ClientId,
-    ) {
-        self.root_node.remove_subscriber(topic_filter, client_id);
-        self.subscribers -= 1;
+    ) -> bool {
+        // Don't copy the path to a subscription that does not exist
+        #[cfg(feature = "rcu")]
+        if !self.root_node.contains_subscriber(&self.interner, &topic_filter, client_id) {
+            return false;
+        }
+        let (removed, pruned_nodes) =
+            self.root_node
+                .remove_subscriber(&mut self.interner, &topic_filter, 0, client_id);
+        self.nodes -= pruned_nodes;
+        if removed {
+            self.subscribers -= 1;
+            if let Some(count) = self.client_subscriptions.get_mut(&client_id) {
+                *count -= 1;
+                if *count == 0 {
+                    self.client_subscriptions.remove(&client_id);
+                }
+            }
+            if self.listener.is_some() {
+                let deactivated = !self.filter_active(&topic_filter);
+                self.emit(SubscriptionEvent::ClientUnsubscribed {
+                    topic_filter: topic_filter.clone(),
+                    client_id,
+                });
+                if deactivated {
+                    self.emit(SubscriptionEvent::FilterDeactivated(topic_filter));
+                }
+            }
+        }
+        removed
+    }
+
+    /// Removes all subscriptions of a client, returns the number of subscriptions removed.
+    /// This visits every node in the tree.
+    pub fn remove_client(&mut self, client_id: ClientId) -> usize {
+        if !self.client_subscriptions.contains_key(&client_id) {
+            return 0;
+        };
+        if self.listener.is_some() {
+            let topic_filters: Vec<TopicFilter> = self
+                .subscriptions()
+                .filter(|(_, subscriber)| subscriber.client_id == client_id)
+                .map(|(topic_filter, _)| topic_filter)
+                .collect();
+            return topic_filters
+                .into_iter()
+                .filter(|topic_filter| self.remove_subscription(topic_filter.clone(), client_id))
+                .count();
+        };
+        let (removed, pruned_nodes) = self.root_node.remove_client(&mut self.interner, client_id);
+        self.nodes -= pruned_nodes;
+        self.subscribers -= removed as u64;
+        self.client_subscriptions.remove(&client_id);
+        removed
     }
 }
 
+/// The number of nodes a single level of the array lookup can hold
+const FRONTIER_SIZE: usize = 4;
+
 /// The TopicNode is the core of the TopicTree structure, the single level wildcard and multilevel
 /// wildcards are seperate fields in the struct to avoid additional hashmap lookups.
 #[derive(Default, Debug, Clone)]
-struct TopicNode {
-    multi_level_wildcard: Option<Box<SubscriptionInfo>>,
-    single_level_wildcard: Option<Box<TopicNode>>,
-    sub_nodes: HashMap<String, TopicNode>,
-    content: SubscriptionInfo,
+pub(crate) struct TopicNode {
+    pub(crate) multi_level_wildcard: Option<Shared<SubscriptionInfo>>,
+    pub(crate) single_level_wildcard: Option<Shared<TopicNode>>,
+    pub(crate) sub_nodes: LevelMap<TopicNode>,
+    pub(crate) content: Content,
 }
 
 impl TopicNode {
     /// All 3 implementations of get routes do the same thing, the array version is currently the
-    /// fastest and therefore used, but it is also the least readable
-    #[allow(dead_code)]
-    fn get_subscriptions(&self, publish_topic: &TopicName, results: &mut Vec<Subscriber>) {
+    /// fastest and therefore used, but it is also the least readable.
+    /// Topics starting with `$` are never matched by a wildcard in the first level, and a multi
+    /// level wildcard also matches its parent level.
+    fn get_subscriptions(
+        &self,
+        symbols: &Interner,
+        publish_topic: &TopicName,
+        results: &mut Vec<Subscriber>,
+    ) {
         let mut vec1: Vec<&TopicNode> = Vec::with_capacity(3);
         let mut vec2: Vec<&TopicNode> = Vec::with_capacity(3);
         vec2.push(self);
         for i in 0..publish_topic.length {
             // let topiclevel = &publish_topic.topic_levels[i];
             let topiclevel = publish_topic.get_part(i).unwrap();
+            let skip_wildcards = i == 0 && topiclevel.starts_with('$');
+            let key = symbols.get(topiclevel);
             std::mem::swap(&mut vec1, &mut vec2);
             vec2.clear();
             for curr_node in vec1.iter() {
-                if let Some(routeinfo) = curr_node.multi_level_wildcard.as_deref() {
-                    results.extend(routeinfo.get_subscriptions())
-                }
-                if let Some(single_wildcard_match) = curr_node.single_level_wildcard.as_deref() {
-                    vec2.push(single_wildcard_match);
+                if !skip_wildcards {
+                    if let Some(routeinfo) = curr_node.multi_level_wildcard.as_deref() {
+                        results.extend(routeinfo.get_subscriptions())
+                    }
+                    if let Some(single_wildcard_match) = curr_node.single_level_wildcard.as_deref() {
+                        vec2.push(single_wildcard_match);
+                    }
                 }
-                if let Some(literal_match) = curr_node.sub_nodes.get(topiclevel) {
+                if let Some(key) = key
+                    && let Some(literal_match) = curr_node.sub_nodes.get(key)
+                {
                     vec2.push(literal_match);
                 }
             }
         }
         for final_node in vec2 {
+            if let Some(routeinfo) = final_node.multi_level_wildcard.as_deref() {
+                results.extend(routeinfo.get_subscriptions())
+            }
             let literal_match = final_node.content.get_subscriptions();
             results.extend(literal_match);
         }
     }
 
-    #[allow(dead_code)]
-    fn get_subscriptions_arr(&self, publish_topic: &TopicName, results: &mut Vec<Subscriber>) {
+    /// Keeps the frontier in fixed size arrays, if more nodes match a single level than fit in
+    /// them the lookup starts over using the vec version.
+    fn get_subscriptions_arr(
+        &self,
+        symbols: &Interner,
+        publish_topic: &TopicName,
+        results: &mut Vec<Subscriber>,
+    ) {
+        let results_start = results.len();
         let mut curr_iter: bool = false;
         let mut iter_len = [0usize, 1usize];
-        let mut iter_arr = [[self, self], [self, self]];
+        let mut iter_arr = [[self; FRONTIER_SIZE]; 2];
         for i in 0..publish_topic.length {
             // let topiclevel = &publish_topic.topic_levels[i];
             let topiclevel = publish_topic.get_part(i).unwrap();
+            let skip_wildcards = i == 0 && topiclevel.starts_with('$');
+            let key = symbols.get(topiclevel);
             iter_len[curr_iter as usize] = 0;
             curr_iter = !curr_iter;
             for j in 0..iter_len[curr_iter as usize] {
                 let curr_node = iter_arr[curr_iter as usize][j];
-                if let Some(routeinfo) = curr_node.multi_level_wildcard.as_deref() {
-                    results.extend(routeinfo.get_subscriptions())
+                if iter_len[!curr_iter as usize] + 2 > FRONTIER_SIZE {
+                    results.truncate(results_start);
+                    return self.get_subscriptions(symbols, publish_topic, results);
                 }
-                if let Some(single_wildcard_match) = curr_node.single_level_wildcard.as_deref() {
-                    iter_arr[!curr_iter as usize][iter_len[!curr_iter as usize]] =
-                        single_wildcard_match;
-                    iter_len[!curr_iter as usize] += 1;
+                if !skip_wildcards {
+                    if let Some(routeinfo) = curr_node.multi_level_wildcard.as_deref() {
+                        results.extend(routeinfo.get_subscriptions())
+                    }
+                    if let Some(single_wildcard_match) = curr_node.single_level_wildcard.as_deref() {
+                        iter_arr[!curr_iter as usize][iter_len[!curr_iter as usize]] =
+                            single_wildcard_match;
+                        iter_len[!curr_iter as usize] += 1;
+                    }
                 }
-                if let Some(literal_match) = curr_node.sub_nodes.get(topiclevel) {
+                if let Some(key) = key
+                    && let Some(literal_match) = curr_node.sub_nodes.get(key)
+                {
                     iter_arr[!curr_iter as usize][iter_len[!curr_iter as usize]] = literal_match;
                     iter_len[!curr_iter as usize] += 1;
                 }
             }
         }
         curr_iter = !curr_iter;
-        for j in 0..iter_len[curr_iter as usize] {
-            let final_node = iter_arr[curr_iter as usize][j];
+        for final_node in &iter_arr[curr_iter as usize][..iter_len[curr_iter as usize]] {
+            if let Some(routeinfo) = final_node.multi_level_wildcard.as_deref() {
+                results.extend(routeinfo.get_subscriptions())
+            }
             let literal_match = final_node.content.get_subscriptions();
             results.extend(literal_match);
         }
     }
 
-    #[allow(dead_code)]
-    fn get_subscriptions_rec(
-        &self,
-        curr_level: usize,
-        publish_topic: &TopicName,
-        results: &mut Vec<Subscriber>,
-    ) {
-        if let Some(routeinfo) = self.multi_level_wildcard.as_deref() {
-            results.extend(routeinfo.get_subscriptions())
-        }
-        if curr_level < publish_topic.length {
-            let topiclevel = publish_topic.get_part(curr_level).unwrap();
-            if let Some(single_wildcard_match) = self.single_level_wildcard.as_deref() {
-                single_wildcard_match.get_subscriptions_rec(curr_level + 1, publish_topic, results)
-            };
-            if let Some(literal_match) = self.sub_nodes.get(topiclevel) {
-                literal_match.get_subscriptions_rec(curr_level + 1, publish_topic, results);
-            }
-        } else {
-            results.extend(self.content.get_subscriptions())
-        }
-    }
-
-    fn add_subscriber(&mut self, topic_filter: TopicFilter, client_id: ClientId, qos: QoS) {
+    /// Returns whether the subscription is new, and the number of nodes that had to be created
+    pub(crate) fn add_subscriber(
+        &mut self,
+        interner: &mut Interner,
+        topic_filter: TopicFilter,
+        client_id: ClientId,
+        qos: QoS,
+    ) -> (bool, usize) {
         let mut curr_node = self;
+        let mut new_nodes = 0;
         for i in 0..topic_filter.length {
             let topic_level = topic_filter.get_part(i).unwrap();
             match topic_level {
                 "+" => {
+                    new_nodes += curr_node.single_level_wildcard.is_none() as usize;
                     curr_node = curr_node.get_single_level_wildcard_node_or_create();
                 }
                 "#" => {
+                    new_nodes += curr_node.multi_level_wildcard.is_none() as usize;
                     curr_node.add_multi_level_wildcard_if_not_exists();
                     let routeinfo = curr_node.multi_level_wildcard.as_deref_mut().unwrap();
-                    match topic_filter.shared_group_name.clone() {
+                    let added = match topic_filter.shared_group_name.clone() {
                         None => {
-                            routeinfo.add_client_subscription(client_id, qos);
+                            routeinfo.add_client_subscription(client_id, qos)
                         }
                         Some(shared_group) => {
                             routeinfo.add_shared_subscription(client_id, qos, shared_group)
                         }
-                    }
-                    return;
+                    };
+                    return (added, new_nodes);
                 }
                 _ => {
-                    curr_node = curr_node.get_sub_node_or_create(topic_level);
+                    let (node, created) = curr_node.get_sub_node_or_create(interner, topic_level);
+                    new_nodes += created as usize;
+                    curr_node = node;
                 }
             }
         }
 
-        match topic_filter.shared_group_name {
+        let added = match topic_filter.shared_group_name {
             None => {
-                curr_node.content.add_client_subscription(client_id, qos);
+                curr_node.content.add_client_subscription(client_id, qos)
             }
             Some(shared_group) => curr_node
                 .content
                 .add_shared_subscription(client_id, qos, shared_group),
-        }
+        };
+        (added, new_nodes)
     }
 
-    fn remove_subscriber(
+    /// Returns whether the subscription existed, and the number of nodes that were pruned because
+    /// they no longer hold any subscriptions
+    pub(crate) fn remove_subscriber(
         &mut self,
-        topic_filter: TopicFilter,
+        interner: &mut Interner,
+        topic_filter: &TopicFilter,
+        level: usize,
         client_id: ClientId,
-    ) {
-        let mut curr_node = self;
-        let sub_info: &mut SubscriptionInfo;
-        let topic_level: &str = "";
-        for i in 0..topic_filter.length {
-            let topic_level = topic_filter.get_part(i).unwrap();
-            match topic_level {
-                "+" => {
-                    if curr_node.single_level_wildcard.is_none() {
-                        return;
-                    } else {
-                        curr_node = curr_node.single_level_wildcard.as_mut().unwrap().deref_mut()
-                    }
+    ) -> (bool, usize) {
+        let Some(topic_level) = topic_filter.get_part(level) else {
+            let removed = self.content.remove_subscription(topic_filter, client_id);
+            return (removed, 0);
+        };
+        match topic_level {
+            "+" => {
+                let Some(node) = self.single_level_wildcard.as_deref_mut() else {
+                    return (false, 0);
+                };
+                let (removed, mut pruned_nodes) =
+                    node.remove_subscriber(interner, topic_filter, level + 1, client_id);
+                if node.is_empty() {
+                    self.single_level_wildcard = None;
+                    pruned_nodes += 1;
                 }
-                "#" => {
-                    if i != topic_filter.length - 1 {return;}
-                    if curr_node.multi_level_wildcard.is_none() {
-                        return;
-                    }
+                (removed, pruned_nodes)
+            }
+            "#" => {
+                let Some(sub_info) = self.multi_level_wildcard.as_deref_mut() else {
+                    return (false, 0);
+                };
+                let removed = sub_info.remove_subscription(topic_filter, client_id);
+                if sub_info.is_empty() {
+                    self.multi_level_wildcard = None;
+                    return (removed, 1);
                 }
-                _ => {
-                    if !curr_node.sub_nodes.contains_key(topic_level) {
-                        return;
-                    } else {
-                        curr_node = curr_node.sub_nodes.get_mut(topic_level).unwrap();
-                    }
+                (removed, 0)
+            }
+            _ => {
+                let node = interner.get(topic_level).and_then(|x| self.sub_nodes.get_mut(x));
+                let Some(node) = node else {
+                    return (false, 0);
+                };
+                let (removed, mut pruned_nodes) =
+                    node.remove_subscriber(interner, topic_filter, level + 1, client_id);
+                if node.is_empty() {
+                    let key = interner.get(topic_level).unwrap();
+                    let (topic_level, _) = remove_level(&mut self.sub_nodes, key).unwrap();
+                    interner.release(&topic_level);
+                    pruned_nodes += 1;
                 }
+                (removed, pruned_nodes)
             }
         }
-        match topic_level {
-            "#" => {sub_info = curr_node.multi_level_wildcard.as_mut().unwrap().deref_mut();}
-            _ => {sub_info = &mut curr_node.content}
+    }
+
+    /// Calls `f` with every SubscriptionInfo that matches the topic, together with the levels of the
+    /// filter it is stored under. Follows the same rules as the lookups.
+    pub(crate) fn visit_matches<'a, F>(
+        &'a self,
+        symbols: &'a Interner,
+        publish_topic: &TopicName,
+        curr_level: usize,
+        levels: &mut Vec<&'a str>,
+        f: &mut F,
+    ) where
+        F: FnMut(&[&'a str], &'a SubscriptionInfo),
+    {
+        let skip_wildcards = curr_level == 0
+            && publish_topic.get_part(0).is_some_and(|x| x.starts_with('$'));
+        if !skip_wildcards && let Some(routeinfo) = self.multi_level_wildcard.as_deref() {
+            levels.push("#");
+            f(levels, routeinfo);
+            levels.pop();
+        }
+        let Some(topic_level) = publish_topic.get_part(curr_level) else {
+            f(levels, &self.content);
+            return;
+        };
+        if !skip_wildcards && let Some(single_wildcard_match) = self.single_level_wildcard.as_deref() {
+            levels.push("+");
+            single_wildcard_match.visit_matches(symbols, publish_topic, curr_level + 1, levels, f);
+            levels.pop();
+        }
+        if let Some(key) = symbols.get(topic_level)
+            && let Some((topic_level, literal_match)) = self.sub_nodes.get_key_value(key)
+        {
+            levels.push(symbols.name(topic_level));
+            literal_match.visit_matches(symbols, publish_topic, curr_level + 1, levels, f);
+            levels.pop();
         }
+    }
 
-        match topic_filter.shared_group_name {
-            None => {
-                sub_info.remove_client_subscription(client_id)
+    /// Returns the number of subscriptions removed, and the number of nodes that were pruned
+    pub(crate) fn remove_client(
+        &mut self,
+        interner: &mut Interner,
+        client_id: ClientId,
+    ) -> (usize, usize) {
+        let mut removed = 0;
+        let mut pruned_nodes = 0;
+        // Under the `rcu` feature changing a part copies it, so only the parts the client is
+        // subscribed in are changed
+        if self.content.contains_client(client_id) {
+            removed += self.content.remove_client(client_id);
+        }
+        if let Some(sub_info) = self.multi_level_wildcard.as_mut()
+            && sub_info.contains_client(client_id)
+        {
+            removed += sub_info.remove_client(client_id);
+            if sub_info.is_empty() {
+                self.multi_level_wildcard = None;
+                pruned_nodes += 1;
+            }
+        }
+        if let Some(node) = self.single_level_wildcard.as_mut()
+            && node.contains_client(client_id)
+        {
+            let (node_removed, node_pruned) = node.remove_client(interner, client_id);
+            removed += node_removed;
+            pruned_nodes += node_pruned;
+            if node.is_empty() {
+                self.single_level_wildcard = None;
+                pruned_nodes += 1;
+            }
+        }
+        #[cfg(not(feature = "rcu"))]
+        self.sub_nodes.retain(|topic_level, node| {
+            let (node_removed, node_pruned) = node.remove_client(interner, client_id);
+            removed += node_removed;
+            pruned_nodes += node_pruned;
+            let empty = node.is_empty();
+            if empty {
+                interner.release(topic_level);
+                pruned_nodes += 1;
+            }
+            !empty
+        });
+        #[cfg(feature = "rcu")]
+        {
+            let topic_levels: Vec<_> = self
+                .sub_nodes
+                .iter()
+                .filter(|(_, node)| node.contains_client(client_id))
+                .map(|(topic_level, _)| topic_level)
+                .cloned()
+                .collect();
+            for topic_level in topic_levels {
+                let node = self.sub_nodes.get_mut(&topic_level).unwrap();
+                let (node_removed, node_pruned) = node.remove_client(interner, client_id);
+                removed += node_removed;
+                pruned_nodes += node_pruned;
+                if node.is_empty() {
+                    self.sub_nodes.remove(&topic_level);
+                    interner.release(&topic_level);
+                    pruned_nodes += 1;
+                }
+            }
+        }
+        (removed, pruned_nodes)
+    }
+
+    /// Whether the client is subscribed to a filter in this node or below it
+    pub(crate) fn contains_client(&self, client_id: ClientId) -> bool {
+        self.content.contains_client(client_id)
+            || self
+                .multi_level_wildcard
+                .as_deref()
+                .is_some_and(|x| x.contains_client(client_id))
+            || self
+                .single_level_wildcard
+                .as_deref()
+                .is_some_and(|x| x.contains_client(client_id))
+            || self.sub_nodes.values().any(|x| x.contains_client(client_id))
+    }
+
+    /// Finds the SubscriptionInfo a filter is stored in, if the path to it exists
+    fn find_subscription_info(
+        &self,
+        symbols: &Interner,
+        topic_filter: &TopicFilter,
+    ) -> Option<&SubscriptionInfo> {
+        let mut curr_node = self;
+        for i in 0..topic_filter.length {
+            match topic_filter.get_part(i).unwrap() {
+                "+" => curr_node = curr_node.single_level_wildcard.as_deref()?,
+                "#" => return curr_node.multi_level_wildcard.as_deref(),
+                topic_level => curr_node = curr_node.sub_nodes.get(symbols.get(topic_level)?)?,
             }
-            Some(shared_group) =>  {
-                sub_info.remove_shared_subscription(client_id, shared_group)
+        }
+        let content: &SubscriptionInfo = &curr_node.content;
+        Some(content)
+    }
+
+    fn contains_subscriber(
+        &self,
+        symbols: &Interner,
+        topic_filter: &TopicFilter,
+        client_id: ClientId,
+    ) -> bool {
+        self.find_subscription_info(symbols, topic_filter)
+            .is_some_and(|sub_info| sub_info.contains_subscription(topic_filter, client_id))
+    }
+
+    /// The number of nodes that adding the filter would create
+    fn missing_nodes(&self, symbols: &Interner, topic_filter: &TopicFilter) -> usize {
+        let mut curr_node = self;
+        for i in 0..topic_filter.length {
+            let next_node = match topic_filter.get_part(i).unwrap() {
+                "+" => curr_node.single_level_wildcard.as_deref(),
+                "#" => {
+                    return curr_node.multi_level_wildcard.is_none() as usize;
+                }
+                topic_level => symbols.get(topic_level).and_then(|x| curr_node.sub_nodes.get(x)),
+            };
+            match next_node {
+                Some(node) => curr_node = node,
+                None => return topic_filter.length - i,
             }
         }
+        0
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.multi_level_wildcard.is_none()
+            && self.single_level_wildcard.is_none()
+            && self.sub_nodes.is_empty()
+            && self.content.is_empty()
     }
 
-    fn get_sub_node_or_create(&mut self, topic_level: &str) -> &mut Self {
-        if !self.sub_nodes.contains_key(topic_level) {
+    /// Returns the sub node, and whether it had to be created
+    fn get_sub_node_or_create(
+        &mut self,
+        interner: &mut Interner,
+        topic_level: &str,
+    ) -> (&mut Self, bool) {
+        let exists = interner
+            .get(topic_level)
+            .is_some_and(|x| self.sub_nodes.contains_key(x));
+        if !exists {
             self.sub_nodes
-                .insert(topic_level.to_owned(), TopicNode::default());
+                .insert(interner.intern(topic_level), TopicNode::default());
         }
-        self.sub_nodes.get_mut(topic_level).unwrap()
+        let node = self.sub_nodes.get_mut(interner.get(topic_level).unwrap()).unwrap();
+        (node, !exists)
     }
 
     fn get_single_level_wildcard_node_or_create(&mut self) -> &mut Self {
         if self.single_level_wildcard.is_none() {
-            self.single_level_wildcard = Some(Box::new(TopicNode::default()));
+            self.single_level_wildcard = Some(Shared::new(TopicNode::default()));
         }
         self.single_level_wildcard.as_mut().unwrap().deref_mut()
     }
 
     fn add_multi_level_wildcard_if_not_exists(&mut self) {
         if self.multi_level_wildcard.is_none() {
-            self.multi_level_wildcard = Some(Box::new(SubscriptionInfo::default()));
+            self.multi_level_wildcard = Some(Shared::new(SubscriptionInfo::default()));
         }
     }
 }
 
 /// The RouteInfo contains all the info about the subscriptions
 #[derive(Default, Debug, Clone)]
-struct SubscriptionInfo {
-    client_subscriptions: HashMap<ClientId, QoS>,
-    shared_subscriptions: Vec<ClientGroup>,
+pub(crate) struct SubscriptionInfo {
+    pub(crate) client_subscriptions: HashMap<ClientId, QoS>,
+    pub(crate) shared_subscriptions: Vec<ClientGroup>,
 }
 
 impl SubscriptionInfo {
-    fn get_subscriptions(&self) -> Vec<Subscriber> {
+    pub(crate) fn get_subscriptions(&self) -> Vec<Subscriber> {
         let mut subs: Vec<Subscriber> = self.client_subscriptions
             .iter()
-            .map(|x| Subscriber {client_id: x.0.clone(), qos: x.1.clone() })
+            .map(|x| Subscriber {client_id: *x.0, qos: *x.1 })
             .collect();
         let shared_sub: Vec<Subscriber> = self
             .shared_subscriptions
@@ -266,43 +841,241 @@ impl SubscriptionInfo {
         subs
     }
 
-    fn add_client_subscription(&mut self, client_id: ClientId, qos: QoS) {
-        self.client_subscriptions.insert(client_id, qos);
+    pub(crate) fn is_empty(&self) -> bool {
+        self.client_subscriptions.is_empty() && self.shared_subscriptions.is_empty()
     }
 
-    fn remove_client_subscription(&mut self, client_id: ClientId) {
-        let _res = self.client_subscriptions.remove(&client_id);
+    /// Returns whether the client was not subscribed yet
+    pub(crate) fn add_client_subscription(&mut self, client_id: ClientId, qos: QoS) -> bool {
+        self.client_subscriptions.insert(client_id, qos).is_none()
     }
 
-    fn add_shared_subscription(&mut self, client_id: ClientId, qos: QoS, shared_group: String) {
+    /// Returns whether the client was not subscribed to the group yet
+    pub(crate) fn add_shared_subscription(
+        &mut self,
+        client_id: ClientId,
+        qos: QoS,
+        shared_group: String,
+    ) -> bool {
         let subscriber = Subscriber {client_id, qos};
         if let Some(group) = self
             .shared_subscriptions
             .iter_mut()
             .find(|x| x.group_id == shared_group)
         {
-            group.add_subscriber(subscriber);
+            group.add_subscriber(subscriber)
         } else {
             self.shared_subscriptions
-                .push(ClientGroup::new(shared_group, subscriber))
+                .push(ClientGroup::new(shared_group, subscriber));
+            true
         }
     }
 
-    fn remove_shared_subscription(&mut self, client_id: ClientId, shared_group: String) {
-        if let Some(group) = self
+    /// Whether the filter, or the shared group of the filter, has at least one subscriber
+    fn filter_active(&self, topic_filter: &TopicFilter) -> bool {
+        match &topic_filter.shared_group_name {
+            None => !self.client_subscriptions.is_empty(),
+            Some(shared_group) => self
+                .shared_subscriptions
+                .iter()
+                .any(|x| &x.group_id == shared_group),
+        }
+    }
+
+    pub(crate) fn contains_subscription(&self, topic_filter: &TopicFilter, client_id: ClientId) -> bool {
+        match &topic_filter.shared_group_name {
+            None => self.client_subscriptions.contains_key(&client_id),
+            Some(shared_group) => self
+                .shared_subscriptions
+                .iter()
+                .find(|x| &x.group_id == shared_group)
+                .is_some_and(|group| group.clients.iter().any(|x| x.client_id == client_id)),
+        }
+    }
+
+    /// Whether the client is subscribed to the filter of this SubscriptionInfo, directly or in a
+    /// shared group
+    pub(crate) fn contains_client(&self, client_id: ClientId) -> bool {
+        self.client_subscriptions.contains_key(&client_id)
+            || self
+                .shared_subscriptions
+                .iter()
+                .any(|group| group.clients.iter().any(|x| x.client_id == client_id))
+    }
+
+    /// Returns whether the client was subscribed
+    pub(crate) fn remove_subscription(&mut self, topic_filter: &TopicFilter, client_id: ClientId) -> bool {
+        match &topic_filter.shared_group_name {
+            None => self.client_subscriptions.remove(&client_id).is_some(),
+            Some(shared_group) => self.remove_shared_subscription(client_id, shared_group),
+        }
+    }
+
+    /// Returns the number of subscriptions of the client that were removed
+    pub(crate) fn remove_client(&mut self, client_id: ClientId) -> usize {
+        let mut removed = self.client_subscriptions.remove(&client_id).is_some() as usize;
+        self.shared_subscriptions.retain_mut(|group| {
+            removed += group.remove_subscriber(client_id) as usize;
+            !group.clients.is_empty()
+        });
+        removed
+    }
+
+    fn remove_shared_subscription(&mut self, client_id: ClientId, shared_group: &str) -> bool {
+        let Some(idx) = self
             .shared_subscriptions
-            .iter_mut()
-            .find(|x| x.group_id == shared_group) {
-            group.remove_subscriber(client_id)
+            .iter()
+            .position(|x| x.group_id == shared_group) else {
+            return false;
+        };
+        let group = &mut self.shared_subscriptions[idx];
+        let removed = group.remove_subscriber(client_id);
+        // An empty group has no client to deliver to
+        if group.clients.is_empty() {
+            self.shared_subscriptions.remove(idx);
+        }
+        removed
+    }
+}
+
+/// A part of the tree that holds subscriptions
+pub(crate) enum Part<'a> {
+    Node(&'a TopicNode),
+    Wildcard(&'a SubscriptionInfo),
+}
+
+/// Iterates over the subscriptions of a part of the tree and everything below it, walking the
+/// nodes with an explicit stack. The filters are built from the levels of the path to each node.
+pub(crate) struct Subscriptions<'a> {
+    symbols: &'a Interner,
+    /// The parts that are still to be visited, with the length of the path to their parent and
+    /// their own level
+    stack: Vec<(usize, Option<&'a str>, Part<'a>)>,
+    /// The path to the part that is being visited
+    levels: Vec<&'a str>,
+    entries: Option<Entries<'a>>,
+}
+
+impl<'a> Subscriptions<'a> {
+    /// `levels` is the path to the part
+    fn new(symbols: &'a Interner, levels: Vec<&'a str>, part: Option<Part<'a>>) -> Self {
+        let stack = part.map(|x| (levels.len(), None, x)).into_iter().collect();
+        Self {
+            symbols,
+            stack,
+            levels,
+            entries: None,
+        }
+    }
+}
+
+impl<'a> Iterator for Subscriptions<'a> {
+    type Item = (TopicFilter, Subscriber);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entries) = self.entries.as_mut()
+                && let Some(entry) = entries.next(&self.levels)
+            {
+                return Some(entry);
+            }
+            let (depth, topic_level, part) = self.stack.pop()?;
+            self.levels.truncate(depth);
+            self.levels.extend(topic_level);
+            let depth = self.levels.len();
+            let sub_info = match part {
+                Part::Wildcard(sub_info) => sub_info,
+                Part::Node(node) => {
+                    for (topic_level, sub_node) in node.sub_nodes.iter() {
+                        let topic_level = self.symbols.name(topic_level);
+                        self.stack.push((depth, Some(topic_level), Part::Node(sub_node)));
+                    }
+                    if let Some(sub_node) = node.single_level_wildcard.as_deref() {
+                        self.stack.push((depth, Some("+"), Part::Node(sub_node)));
+                    }
+                    if let Some(sub_info) = node.multi_level_wildcard.as_deref() {
+                        self.stack.push((depth, Some("#"), Part::Wildcard(sub_info)));
+                    }
+                    // The root holds no subscriptions
+                    if depth == 0 {
+                        continue;
+                    }
+                    &node.content
+                }
+            };
+            self.entries = Some(Entries::new(sub_info, &self.levels));
+        }
+    }
+}
+
+/// The subscriptions of a SubscriptionInfo that were not returned yet
+struct Entries<'a> {
+    filter: Option<TopicFilter>,
+    clients: std::collections::hash_map::Iter<'a, ClientId, QoS>,
+    groups: std::slice::Iter<'a, ClientGroup>,
+    group: Option<(TopicFilter, std::slice::Iter<'a, Subscriber>)>,
+}
+
+impl<'a> Entries<'a> {
+    fn new(sub_info: &'a SubscriptionInfo, levels: &[&str]) -> Self {
+        let filter = (!sub_info.client_subscriptions.is_empty())
+            .then(|| TopicFilter::from_levels(None, levels));
+        Self {
+            filter,
+            clients: sub_info.client_subscriptions.iter(),
+            groups: sub_info.shared_subscriptions.iter(),
+            group: None,
         }
     }
+
+    /// `levels` is the path to the SubscriptionInfo
+    fn next(&mut self, levels: &[&str]) -> Option<(TopicFilter, Subscriber)> {
+        if let Some(filter) = self.filter.as_ref()
+            && let Some((client_id, qos)) = self.clients.next()
+        {
+            let subscriber = Subscriber { client_id: *client_id, qos: *qos };
+            return Some((filter.clone(), subscriber));
+        }
+        loop {
+            if let Some((filter, clients)) = self.group.as_mut()
+                && let Some(subscriber) = clients.next()
+            {
+                return Some((filter.clone(), subscriber.clone()));
+            }
+            let group = self.groups.next()?;
+            let filter = TopicFilter::from_levels(Some(&group.group_id), levels);
+            self.group = Some((filter, group.clients.iter()));
+        }
+    }
+}
+
+/// Downgrades the QoS of every subscriber to the QoS of the publish
+pub(crate) fn with_qos(mut results: Vec<Subscriber>, publish_qos: QoS) -> Vec<Subscriber> {
+    for subscriber in results.iter_mut() {
+        subscriber.qos = subscriber.qos.min(publish_qos);
+    }
+    results
+}
+
+/// Keeps every client once, with the highest QoS it was matched with
+pub(crate) fn deduplicated(mut results: Vec<Subscriber>) -> Vec<Subscriber> {
+    results.sort_unstable_by(|a, b| a.client_id.cmp(&b.client_id).then(b.qos.cmp(&a.qos)));
+    results.dedup_by_key(|x| x.client_id);
+    results
+}
+
+fn subscription_entry(
+    (topic_filter, subscriber): (TopicFilter, Subscriber),
+) -> (TopicFilter, ClientId, QoS, Option<String>) {
+    let shared_group = topic_filter.shared_group().map(str::to_owned);
+    (topic_filter, subscriber.client_id, subscriber.qos, shared_group)
 }
 
 /// The ClientGroup represents a single shared subscription.
 #[derive(Debug, Clone)]
-struct ClientGroup {
-    group_id: String,
-    clients: Vec<Subscriber>,
+pub(crate) struct ClientGroup {
+    pub(crate) group_id: String,
+    pub(crate) clients: Vec<Subscriber>,
 }
 
 impl ClientGroup {
@@ -317,25 +1090,36 @@ impl ClientGroup {
         self.clients[idx].clone()
     }
 
-    fn get_next_client(&self) -> Subscriber {
+    pub(crate) fn get_next_client(&self) -> Subscriber {
         self.get_client_by_number(random())
     }
 
-    fn add_subscriber(&mut self, subscriber: Subscriber) {
+    /// Returns whether the client was not in the group yet, an existing member only has its QoS
+    /// updated
+    fn add_subscriber(&mut self, subscriber: Subscriber) -> bool {
+        if let Some(client) = self.clients
+            .iter_mut()
+            .find(|x| x.client_id == subscriber.client_id) {
+            client.qos = subscriber.qos;
+            return false;
+        }
         self.clients.push(subscriber);
+        true
     }
 
-    fn remove_subscriber(&mut self, client_id: ClientId) {
+    fn remove_subscriber(&mut self, client_id: ClientId) -> bool {
         if let Some(idx) = self.clients
             .iter()
             .position(|x| x.client_id == client_id) {
             self.clients.remove(idx);
+            return true;
         }
-
+        false
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Subscriber {
     pub client_id: ClientId,
     pub qos: QoS,
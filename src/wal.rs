@@ -0,0 +1,347 @@
+//! A write-ahead log that makes a MqttTopicTree durable between snapshots.
+//!
+//! A durable tree lives in a directory with a snapshot and a log. Every write is appended to the
+//! log and synced to disk before it is applied, recovery loads the snapshot and replays the log on
+//! top of it, and a checkpoint replaces the snapshot and empties the log.
+//!
+//! ```text
+//! log    = magic:[u8; 4] version:u16 record*
+//! record = len:u32 crc:u32 header_crc:u32 payload
+//! ```
+//!
+//! The `crc` covers the payload and the `header_crc` the eight bytes before it, so a damaged length
+//! is detected before it is used to find the next record.
+//!
+//! Writes that are committed together are wrapped in batch begin and end records, so they are
+//! either replayed completely or not at all. A final record that was only partially written, or a
+//! batch without its end record, is truncated from the log during recovery. A bad record that is
+//! followed by more records can't be the result of a crash, recovery fails on it and leaves the log
+//! untouched.
+
+use crate::snapshot::{SnapshotError, crc32};
+use crate::sync::TopicTreeOperations;
+use crate::sync::TopicTreeOperations::{AddSubscription, RemoveClient, RemoveSubscription};
+use crate::{ClientId, QoS, TopicFilter, TopicLimits, TopicTree};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const SNAPSHOT_FILE: &str = "topic_tree.snapshot";
+pub const LOG_FILE: &str = "topic_tree.wal";
+const SNAPSHOT_TMP_FILE: &str = "topic_tree.snapshot.tmp";
+
+const MAGIC: [u8; 4] = *b"MTTL";
+const VERSION: u16 = 1;
+const HEADER_LEN: u64 = 6;
+const RECORD_HEADER_LEN: usize = 12;
+
+const ADD_SUBSCRIPTION: u8 = 0;
+const REMOVE_SUBSCRIPTION: u8 = 1;
+const REMOVE_CLIENT: u8 = 2;
+const BATCH_BEGIN: u8 = 3;
+const BATCH_END: u8 = 4;
+
+/// The reason a durable tree could not be recovered
+#[derive(Debug)]
+pub enum RecoveryError {
+    Io(io::Error),
+    Snapshot(SnapshotError),
+    /// The record at the offset failed its checksum or could not be decoded, while it was not the
+    /// last record of the log. An offset of 0 means the log has the wrong header.
+    CorruptLog { offset: u64 },
+}
+
+impl fmt::Display for RecoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecoveryError::Io(e) => write!(f, "could not read the write-ahead log: {e}"),
+            RecoveryError::Snapshot(e) => write!(f, "could not read the snapshot: {e}"),
+            RecoveryError::CorruptLog { offset } => {
+                write!(f, "write-ahead log is corrupt at offset {offset}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecoveryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RecoveryError::Io(e) => Some(e),
+            RecoveryError::Snapshot(e) => Some(e),
+            RecoveryError::CorruptLog { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for RecoveryError {
+    fn from(value: io::Error) -> Self {
+        RecoveryError::Io(value)
+    }
+}
+
+impl From<SnapshotError> for RecoveryError {
+    fn from(value: SnapshotError) -> Self {
+        RecoveryError::Snapshot(value)
+    }
+}
+
+/// The log file of a durable tree, together with the directory its snapshot lives in
+pub(crate) struct WriteAheadLog {
+    dir: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl WriteAheadLog {
+    /// Loads the snapshot in the directory and replays the log on top of it, the directory and
+    /// files are created if they don't exist yet
+    pub(crate) fn recover(
+        dir: &Path,
+        limits: TopicLimits,
+    ) -> Result<(TopicTree, WriteAheadLog), RecoveryError> {
+        fs::create_dir_all(dir)?;
+        let mut topic_tree = match File::open(dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => TopicTree::read_snapshot(io::BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => TopicTree::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOG_FILE))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            file.write_all(&MAGIC)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+            buf.extend_from_slice(&MAGIC);
+            buf.extend_from_slice(&VERSION.to_le_bytes());
+        }
+        if buf.len() < HEADER_LEN as usize
+            || buf[..MAGIC.len()] != MAGIC
+            || buf[MAGIC.len()..HEADER_LEN as usize] != VERSION.to_le_bytes()
+        {
+            return Err(RecoveryError::CorruptLog { offset: 0 });
+        };
+
+        let mut pos = HEADER_LEN as usize;
+        // The end of the last record that is known to be complete, including its batch
+        let mut valid_len = pos;
+        let mut batch: Option<Vec<TopicTreeOperations>> = None;
+        while let Some((payload, next_pos)) = read_record(&buf, pos)? {
+            let corrupt = || RecoveryError::CorruptLog { offset: pos as u64 };
+            let record = decode_record(payload).ok_or_else(corrupt)?;
+            match record {
+                Record::BatchBegin => {
+                    if batch.is_some() {
+                        return Err(corrupt());
+                    }
+                    batch = Some(Vec::new());
+                }
+                Record::BatchEnd => {
+                    let operations = batch.take().ok_or_else(corrupt)?;
+                    for operation in operations.iter() {
+                        replay(operation, &mut topic_tree, valid_len)?;
+                    }
+                    valid_len = next_pos;
+                }
+                Record::Operation(operation) => match batch.as_mut() {
+                    Some(operations) => operations.push(operation),
+                    None => {
+                        replay(&operation, &mut topic_tree, pos)?;
+                        valid_len = next_pos;
+                    }
+                },
+            }
+            pos = next_pos;
+        }
+        // Drop a torn final record or a batch that was never finished
+        if valid_len < buf.len() {
+            file.set_len(valid_len as u64)?;
+        }
+        file.seek(SeekFrom::Start(valid_len as u64))?;
+        file.sync_all()?;
+        topic_tree.set_limits(limits);
+
+        let wal = WriteAheadLog {
+            dir: dir.to_owned(),
+            file,
+            len: valid_len as u64,
+        };
+        Ok((topic_tree, wal))
+    }
+
+    /// Appends the operations and syncs them to disk, more than one operation is written as a batch
+    pub(crate) fn append(&mut self, operations: &[TopicTreeOperations]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        let batch = operations.len() > 1;
+        if batch {
+            encode_record(&mut buf, &[BATCH_BEGIN]);
+        }
+        let mut payload = Vec::new();
+        for operation in operations {
+            payload.clear();
+            encode_operation(&mut payload, operation);
+            encode_record(&mut buf, &payload);
+        }
+        if batch {
+            encode_record(&mut buf, &[BATCH_END]);
+        }
+        let res = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = res {
+            // Don't leave a partial record behind for the next append to follow
+            let _ = self.file.set_len(self.len);
+            let _ = self.file.seek(SeekFrom::Start(self.len));
+            return Err(e);
+        }
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Replaces the snapshot with the given tree and empties the log
+    pub(crate) fn checkpoint(&mut self, topic_tree: &TopicTree) -> io::Result<()> {
+        let tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+        let mut tmp_file = File::create(&tmp_path)?;
+        if let Err(e) = topic_tree.write_snapshot(&mut tmp_file) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+        // If we crash before the log is emptied the operations are replayed on top of the new
+        // snapshot, which leaves the tree in the same state
+        self.file.set_len(HEADER_LEN)?;
+        self.file.seek(SeekFrom::Start(HEADER_LEN))?;
+        self.file.sync_all()?;
+        self.len = HEADER_LEN;
+        Ok(())
+    }
+}
+
+/// Applies a logged operation that is part of the record, or batch, at the offset. The operations
+/// were checked against the limits when they were logged, so they are replayed before the limits
+/// of the recovered tree are set.
+fn replay(
+    operation: &TopicTreeOperations,
+    topic_tree: &mut TopicTree,
+    offset: usize,
+) -> Result<(), RecoveryError> {
+    operation
+        .apply_to(topic_tree)
+        .map_err(|_| RecoveryError::CorruptLog { offset: offset as u64 })
+}
+
+enum Record {
+    Operation(TopicTreeOperations),
+    BatchBegin,
+    BatchEnd,
+}
+
+/// Returns the payload of the record at `pos` and the position of the next record. Returns None at
+/// the end of the log, or if the last record is incomplete or fails its checksum, which is what a
+/// crash in the middle of an append leaves behind.
+fn read_record(buf: &[u8], pos: usize) -> Result<Option<(&[u8], usize)>, RecoveryError> {
+    let Some(header) = buf.get(pos..pos + RECORD_HEADER_LEN) else {
+        return Ok(None);
+    };
+    let corrupt = RecoveryError::CorruptLog { offset: pos as u64 };
+    let header_checksum = u32::from_le_bytes(header[8..].try_into().unwrap());
+    if crc32(&header[..8]) != header_checksum {
+        // The length can't be trusted, so this is only a torn write if nothing follows the header
+        return match pos + RECORD_HEADER_LEN == buf.len() {
+            true => Ok(None),
+            false => Err(corrupt),
+        };
+    };
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let start = pos + RECORD_HEADER_LEN;
+    let Some(payload) = buf.get(start..start + len) else {
+        return Ok(None);
+    };
+    if crc32(payload) != checksum {
+        return match start + len == buf.len() {
+            true => Ok(None),
+            false => Err(corrupt),
+        };
+    };
+    Ok(Some((payload, start + len)))
+}
+
+fn encode_record(buf: &mut Vec<u8>, payload: &[u8]) {
+    let header_start = buf.len();
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(payload).to_le_bytes());
+    let header_checksum = crc32(&buf[header_start..]);
+    buf.extend_from_slice(&header_checksum.to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+fn encode_operation(buf: &mut Vec<u8>, operation: &TopicTreeOperations) {
+    let encode_filter = |buf: &mut Vec<u8>, topic_filter: &TopicFilter| {
+        buf.extend_from_slice(&(topic_filter.as_str().len() as u32).to_le_bytes());
+        buf.extend_from_slice(topic_filter.as_str().as_bytes());
+    };
+    match operation {
+        AddSubscription(topic_filter, client_id, qos) => {
+            buf.push(ADD_SUBSCRIPTION);
+            buf.extend_from_slice(&client_id.to_le_bytes());
+            buf.push(*qos as u8);
+            encode_filter(buf, topic_filter);
+        }
+        RemoveSubscription(topic_filter, client_id) => {
+            buf.push(REMOVE_SUBSCRIPTION);
+            buf.extend_from_slice(&client_id.to_le_bytes());
+            encode_filter(buf, topic_filter);
+        }
+        RemoveClient(client_id) => {
+            buf.push(REMOVE_CLIENT);
+            buf.extend_from_slice(&client_id.to_le_bytes());
+        }
+    }
+}
+
+fn decode_record(payload: &[u8]) -> Option<Record> {
+    let (tag, mut rest) = payload.split_first()?;
+    let mut take = |len: usize| -> Option<&[u8]> {
+        let (head, tail) = rest.split_at_checked(len)?;
+        rest = tail;
+        Some(head)
+    };
+    let record = match *tag {
+        BATCH_BEGIN => Record::BatchBegin,
+        BATCH_END => Record::BatchEnd,
+        REMOVE_CLIENT => {
+            let client_id = ClientId::from_le_bytes(take(8)?.try_into().unwrap());
+            Record::Operation(RemoveClient(client_id))
+        }
+        ADD_SUBSCRIPTION | REMOVE_SUBSCRIPTION => {
+            let client_id = ClientId::from_le_bytes(take(8)?.try_into().unwrap());
+            let qos = match *tag {
+                ADD_SUBSCRIPTION => Some(QoS::try_from(take(1)?[0]).ok()?),
+                _ => None,
+            };
+            let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+            let topic_filter = std::str::from_utf8(take(len)?).ok()?;
+            let topic_filter = TopicFilter::try_from(topic_filter).ok()?;
+            match qos {
+                Some(qos) => Record::Operation(AddSubscription(topic_filter, client_id, qos)),
+                None => Record::Operation(RemoveSubscription(topic_filter, client_id)),
+            }
+        }
+        _ => return None,
+    };
+    if !rest.is_empty() {
+        return None;
+    };
+    Some(record)
+}